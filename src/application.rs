@@ -1,20 +1,201 @@
-use tendermint_abci::Application;
-use tendermint_proto::v0_37::abci::{request::Value, response, Request, Response};
+use std::fmt;
+use std::str::FromStr;
+
+use prost::Message;
+use tendermint_proto::v0_37::abci::{
+    self as v0_37, response_process_proposal, RequestApplySnapshotChunk, RequestBeginBlock,
+    RequestCheckTx, RequestDeliverTx, RequestEcho, RequestEndBlock, RequestInfo, RequestInitChain,
+    RequestLoadSnapshotChunk, RequestOfferSnapshot, RequestPrepareProposal, RequestProcessProposal,
+    RequestQuery, ResponseApplySnapshotChunk, ResponseBeginBlock, ResponseCheckTx, ResponseCommit,
+    ResponseDeliverTx, ResponseEcho, ResponseEndBlock, ResponseFlush, ResponseInfo,
+    ResponseInitChain, ResponseListSnapshots, ResponseLoadSnapshotChunk, ResponseOfferSnapshot,
+    ResponsePrepareProposal, ResponseProcessProposal, ResponseQuery,
+};
+use tendermint_proto::v0_38::abci::{
+    self as v0_38, response_verify_vote_extension, RequestExtendVote, RequestFinalizeBlock,
+    RequestVerifyVoteExtension, ResponseExtendVote, ResponseFinalizeBlock,
+    ResponseVerifyVoteExtension,
+};
+
+/// ABCI protocol spoken with the node, chosen at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AbciVersion {
+    /// Tendermint 0.37, blocks are executed by BeginBlock, DeliverTx and
+    /// EndBlock.
+    #[default]
+    V0_37,
+    /// CometBFT 0.38, blocks are executed by FinalizeBlock.
+    V0_38,
+}
+
+impl FromStr for AbciVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0.37" => Ok(AbciVersion::V0_37),
+            "0.38" => Ok(AbciVersion::V0_38),
+            _ => Err(format!(
+                "Unsupported ABCI version {s}, expected 0.37 or 0.38"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AbciVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbciVersion::V0_37 => write!(f, "0.37"),
+            AbciVersion::V0_38 => write!(f, "0.38"),
+        }
+    }
+}
+
+/// An ABCI application, with the messages of ABCI 0.37 and the ones ABCI
+/// 0.38 added.
+///
+/// Messages shared by both versions take their 0.37 types. The 0.38
+/// dispatcher converts them, see [`convert`].
+pub trait Application: Send + Clone + 'static {
+    fn echo(&self, request: RequestEcho) -> ResponseEcho {
+        ResponseEcho {
+            message: request.message,
+        }
+    }
+
+    fn info(&self, _request: RequestInfo) -> ResponseInfo {
+        Default::default()
+    }
+
+    fn init_chain(&self, _request: RequestInitChain) -> ResponseInitChain {
+        Default::default()
+    }
+
+    fn query(&self, _request: RequestQuery) -> ResponseQuery {
+        Default::default()
+    }
+
+    fn check_tx(&self, _request: RequestCheckTx) -> ResponseCheckTx {
+        Default::default()
+    }
+
+    fn begin_block(&self, _request: RequestBeginBlock) -> ResponseBeginBlock {
+        Default::default()
+    }
+
+    fn deliver_tx(&self, _request: RequestDeliverTx) -> ResponseDeliverTx {
+        Default::default()
+    }
+
+    fn end_block(&self, _request: RequestEndBlock) -> ResponseEndBlock {
+        Default::default()
+    }
+
+    fn flush(&self) -> ResponseFlush {
+        ResponseFlush {}
+    }
+
+    fn commit(&self) -> ResponseCommit {
+        Default::default()
+    }
+
+    fn list_snapshots(&self) -> ResponseListSnapshots {
+        Default::default()
+    }
+
+    fn offer_snapshot(&self, _request: RequestOfferSnapshot) -> ResponseOfferSnapshot {
+        Default::default()
+    }
+
+    fn load_snapshot_chunk(&self, _request: RequestLoadSnapshotChunk) -> ResponseLoadSnapshotChunk {
+        Default::default()
+    }
+
+    fn apply_snapshot_chunk(
+        &self,
+        _request: RequestApplySnapshotChunk,
+    ) -> ResponseApplySnapshotChunk {
+        Default::default()
+    }
+
+    /// Proposes the transactions of the mempool that fit in `max_tx_bytes`.
+    fn prepare_proposal(&self, request: RequestPrepareProposal) -> ResponsePrepareProposal {
+        let max_tx_bytes = usize::try_from(request.max_tx_bytes).unwrap_or(0);
+        let mut bytes = 0;
+        let txs = request
+            .txs
+            .into_iter()
+            .take_while(|tx| {
+                bytes += tx.len();
+                bytes <= max_tx_bytes
+            })
+            .collect();
+        ResponsePrepareProposal { txs }
+    }
+
+    fn process_proposal(&self, _request: RequestProcessProposal) -> ResponseProcessProposal {
+        ResponseProcessProposal {
+            status: response_process_proposal::ProposalStatus::Accept as i32,
+        }
+    }
+
+    fn extend_vote(&self, _request: RequestExtendVote) -> ResponseExtendVote {
+        Default::default()
+    }
+
+    fn verify_vote_extension(
+        &self,
+        _request: RequestVerifyVoteExtension,
+    ) -> ResponseVerifyVoteExtension {
+        ResponseVerifyVoteExtension {
+            status: response_verify_vote_extension::VerifyStatus::Accept as i32,
+        }
+    }
+
+    /// Executes a whole block, BeginBlock, DeliverTx and EndBlock of 0.37 in
+    /// one message.
+    fn finalize_block(&self, request: RequestFinalizeBlock) -> ResponseFinalizeBlock;
+}
+
+/// Re-encodes a message of one ABCI version as the same message of the
+/// other. Both versions number the fields of shared messages alike, fields
+/// one version lacks are dropped.
+pub(crate) fn convert<M: Message, N: Message + Default>(message: M) -> N {
+    N::decode(message.encode_to_vec().as_slice()).expect("shared ABCI message: same encoding")
+}
+
+/// Error answered to a request without a value, which is also how a value of
+/// a type newer than the protocol decodes. The node gets the error and the
+/// connection stays open.
+fn missing_value() -> String {
+    let error = "Request has no value or one of an unsupported type".to_string();
+    tracing::error!("{}", error);
+    error
+}
 
 /// Provides a mechanism for the [`Server`] to execute incoming requests while
 /// expecting the correct response types.
-pub trait RequestDispatcher {
+pub trait RequestDispatcher<Request, Response> {
     /// Executes the relevant application method based on the type of the
     /// request, and produces the corresponding response.
     fn handle(&self, request: Request) -> Response;
 }
 
 // Implement `RequestDispatcher` for all `Application`s.
-impl<A: Application> RequestDispatcher for A {
-    fn handle(&self, request: Request) -> Response {
+impl<A: Application> RequestDispatcher<v0_37::Request, v0_37::Response> for A {
+    fn handle(&self, request: v0_37::Request) -> v0_37::Response {
+        use v0_37::{request::Value, response};
+
         tracing::debug!("Incoming request: {:?}", request);
-        Response {
-            value: Some(match request.value.unwrap() {
+        let Some(value) = request.value else {
+            return v0_37::Response {
+                value: Some(response::Value::Exception(v0_37::ResponseException {
+                    error: missing_value(),
+                })),
+            };
+        };
+        v0_37::Response {
+            value: Some(match value {
                 Value::Echo(req) => response::Value::Echo(self.echo(req)),
                 Value::Flush(_) => response::Value::Flush(self.flush()),
                 Value::Info(req) => response::Value::Info(self.info(req)),
@@ -45,3 +226,58 @@ impl<A: Application> RequestDispatcher for A {
         }
     }
 }
+
+impl<A: Application> RequestDispatcher<v0_38::Request, v0_38::Response> for A {
+    fn handle(&self, request: v0_38::Request) -> v0_38::Response {
+        use v0_38::{request::Value, response};
+
+        tracing::debug!("Incoming request: {:?}", request);
+        let Some(value) = request.value else {
+            return v0_38::Response {
+                value: Some(response::Value::Exception(v0_38::ResponseException {
+                    error: missing_value(),
+                })),
+            };
+        };
+        v0_38::Response {
+            value: Some(match value {
+                Value::Echo(req) => response::Value::Echo(convert(self.echo(convert(req)))),
+                Value::Flush(_) => response::Value::Flush(convert(self.flush())),
+                Value::Info(req) => response::Value::Info(convert(self.info(convert(req)))),
+                Value::InitChain(req) => {
+                    response::Value::InitChain(convert(self.init_chain(convert(req))))
+                }
+                Value::Query(req) => response::Value::Query(convert(self.query(convert(req)))),
+                Value::CheckTx(req) => {
+                    response::Value::CheckTx(convert(self.check_tx(convert(req))))
+                }
+                Value::Commit(_) => response::Value::Commit(convert(self.commit())),
+                Value::ListSnapshots(_) => {
+                    response::Value::ListSnapshots(convert(self.list_snapshots()))
+                }
+                Value::OfferSnapshot(req) => {
+                    response::Value::OfferSnapshot(convert(self.offer_snapshot(convert(req))))
+                }
+                Value::LoadSnapshotChunk(req) => response::Value::LoadSnapshotChunk(convert(
+                    self.load_snapshot_chunk(convert(req)),
+                )),
+                Value::ApplySnapshotChunk(req) => response::Value::ApplySnapshotChunk(convert(
+                    self.apply_snapshot_chunk(convert(req)),
+                )),
+                Value::PrepareProposal(req) => {
+                    response::Value::PrepareProposal(convert(self.prepare_proposal(convert(req))))
+                }
+                Value::ProcessProposal(req) => {
+                    response::Value::ProcessProposal(convert(self.process_proposal(convert(req))))
+                }
+                Value::ExtendVote(req) => response::Value::ExtendVote(self.extend_vote(req)),
+                Value::VerifyVoteExtension(req) => {
+                    response::Value::VerifyVoteExtension(self.verify_vote_extension(req))
+                }
+                Value::FinalizeBlock(req) => {
+                    response::Value::FinalizeBlock(self.finalize_block(req))
+                }
+            }),
+        }
+    }
+}
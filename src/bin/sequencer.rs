@@ -1,43 +1,523 @@
-use consensus_app::transaction::{Transaction, TransactionType};
-use tendermint_rpc::{endpoint::broadcast::tx_sync::Response, Client, HttpClient};
+use std::fmt;
+use std::process::ExitCode;
 
-const DECLARE: &str = "declare";
-const DEPLOY: &str = "deploy";
+use clap::{Parser, Subcommand, ValueEnum};
+use consensus_app::transaction::{
+    class_hash, contract_address, Transaction, TransactionError, TransactionType,
+};
+use consensus_app::wire;
+use serde::{Deserialize, Serialize};
+use starknet_crypto::{get_public_key, Felt as FieldElement};
+use tendermint::{abci::Code, Hash};
+use tendermint_rpc::{Client, HttpClient};
+
+const PROGRAM: &str = "./src/data/my_contract_hello.contract_class.json";
+
+/// Account of the genesis `app_state` described in the README.
+const ACCOUNT_ADDRESS: &str = "0x64b48806902a367c8598f4f95c305e8c1a1acba5f082d294a43793113115691";
+const PRIVATE_KEY: &str = "0x71d7bb07b9a64f6f78ac4c816aff4da9";
+
+/// Exit code of a transaction the node rejected, errors before broadcasting
+/// have their own codes.
+const REJECTED_EXIT_CODE: u8 = 8;
+
+/// Signs Starknet transactions and broadcasts them to a Tendermint node.
+#[derive(Parser)]
+#[command(name = "sequencer")]
+struct Cli {
+    /// RPC endpoint of the Tendermint node.
+    #[arg(long, global = true, default_value = "http://127.0.0.1:26657")]
+    rpc_url: String,
+    /// Chain the transaction hash commits to, the node's network by default.
+    #[arg(long, global = true)]
+    chain_id: Option<String>,
+    #[arg(long, global = true, value_enum, default_value_t = BroadcastMode::Sync)]
+    broadcast_mode: BroadcastMode,
+    #[arg(long, global = true, value_enum, default_value_t = Output::Human)]
+    output: Output,
+    /// Account signing the transaction.
+    #[arg(long, global = true, default_value = ACCOUNT_ADDRESS)]
+    account: String,
+    #[arg(long, global = true, default_value = PRIVATE_KEY)]
+    private_key: String,
+    /// Account nonce, the next one the node expects by default.
+    #[arg(long, global = true)]
+    nonce: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Transaction(TransactionCommand),
+    /// Signs the transactions of a JSON array or NDJSON file, `-` for stdin,
+    /// and broadcasts them in batches.
+    ///
+    /// Each entry is a subcommand with its arguments, such as
+    /// `{"invoke": {"address": "0x1", "key": "0x2", "value": "0x3"}}`.
+    Batch {
+        #[arg(default_value = "-")]
+        file: String,
+        /// Most transactions in a batch.
+        #[arg(long, default_value_t = 16)]
+        max_txs: usize,
+        /// Most encoded bytes in a batch, a larger transaction is sent alone.
+        #[arg(long, default_value_t = 1048576)]
+        max_bytes: usize,
+    },
+}
+
+/// Transaction signed by the account, also the entries of a batch file.
+#[derive(Clone, Subcommand, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TransactionCommand {
+    /// Declares a Sierra class with the hash of its compiled CASM class.
+    Declare {
+        #[arg(long, default_value = PROGRAM)]
+        #[serde(default = "program")]
+        class: String,
+        /// CASM class compiled from `class`, which Scarb builds with
+        /// `casm = true`.
+        #[arg(long)]
+        compiled_class: String,
+    },
+    /// Deploys an instance of a declared class from the account.
+    Deploy {
+        /// Sierra class whose hash is deployed.
+        #[arg(long, default_value = PROGRAM)]
+        #[serde(default = "program")]
+        class: String,
+        #[arg(long, default_value = "0x2")]
+        #[serde(default = "deploy_salt")]
+        salt: String,
+        #[arg(long, value_delimiter = ',', default_value = "0x64696e616d6f")]
+        #[serde(default)]
+        calldata: Vec<String>,
+    },
+    /// Writes `value` to `key` in the storage of `address`.
+    Invoke {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        key: String,
+        #[arg(long)]
+        value: String,
+    },
+    /// Deploys a new account of a declared class holding the signer's key.
+    #[command(alias = "deploy_account")]
+    DeployAccount {
+        #[arg(long, default_value = PROGRAM)]
+        #[serde(default = "program")]
+        class: String,
+        #[arg(long, default_value = "0x1")]
+        #[serde(default = "account_salt")]
+        salt: String,
+    },
+}
+
+fn program() -> String {
+    PROGRAM.to_string()
+}
+
+fn deploy_salt() -> String {
+    "0x2".to_string()
+}
+
+fn account_salt() -> String {
+    "0x1".to_string()
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BroadcastMode {
+    /// Returns without waiting for CheckTx.
+    Async,
+    /// Waits for CheckTx.
+    Sync,
+    /// Waits for the transaction to be committed in a block.
+    Commit,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Human,
+    Json,
+}
+
+/// Reasons the sequencer fails, each with its own exit code. Clap exits with 2
+/// on invalid arguments.
+#[derive(Debug)]
+enum Error {
+    Rpc(tendermint_rpc::Error),
+    InvalidArgument {
+        name: &'static str,
+        value: String,
+    },
+    Transaction(TransactionError),
+    BatchFile {
+        path: String,
+        source: serde_json::Error,
+    },
+}
+
+impl Error {
+    fn code(&self) -> u8 {
+        match self {
+            Error::Rpc(_) => 1,
+            Error::InvalidArgument { .. } => 2,
+            Error::Transaction(e) => e.code(),
+            Error::BatchFile { .. } => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Rpc(e) => write!(f, "RPC request failed: {e}"),
+            Error::InvalidArgument { name, value } => write!(f, "Invalid {name} {value}"),
+            Error::Transaction(e) => write!(f, "{e}"),
+            Error::BatchFile { path, source } => {
+                write!(f, "Invalid transaction in the batch file {path}: {source}")
+            }
+        }
+    }
+}
+
+impl From<tendermint_rpc::Error> for Error {
+    fn from(e: tendermint_rpc::Error) -> Self {
+        Error::Rpc(e)
+    }
+}
+
+impl From<TransactionError> for Error {
+    fn from(e: TransactionError) -> Self {
+        Error::Transaction(e)
+    }
+}
+
+/// Outcome of a broadcast, `height` and the DeliverTx result are only known
+/// once committed.
+#[derive(Serialize)]
+struct Broadcast {
+    tx_hash: String,
+    transactions: Vec<String>,
+    code: u32,
+    log: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deliver_code: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deliver_log: Option<String>,
+}
+
+impl Broadcast {
+    fn checked(tx_hash: Hash, code: Code, log: String, transactions: Vec<String>) -> Self {
+        Self {
+            tx_hash: tx_hash.to_string(),
+            transactions,
+            code: code.value(),
+            log,
+            height: None,
+            deliver_code: None,
+            deliver_log: None,
+        }
+    }
+
+    fn is_err(&self) -> bool {
+        self.code != 0 || self.deliver_code.is_some_and(|code| code != 0)
+    }
+}
+
+impl fmt::Display for Broadcast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tendermint tx {}", self.tx_hash)?;
+        for hash in &self.transactions {
+            writeln!(f, "  transaction {hash}")?;
+        }
+        write!(f, "CheckTx code {}", self.code)?;
+        if !self.log.is_empty() {
+            write!(f, ": {}", self.log)?;
+        }
+        if let (Some(height), Some(code)) = (self.height, self.deliver_code) {
+            write!(f, "\nDeliverTx code {code} at height {height}")?;
+            match &self.deliver_log {
+                Some(log) if !log.is_empty() => write!(f, ": {log}")?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let args: Vec<_> = std::env::args().collect();
-    let error_message = "Extra argument \"invoke\" or \"deploy\" should be provided";
-    if args.len() != 2 {
-        println!("{error_message}");
-        return;
-    }
-
-    let tx = match args[1].as_str() {
-        DECLARE => Transaction::with_type(TransactionType::Declare {
-            program: "./src/data/my_contract_hello.contract_class.json".to_string(),
-        })
-        .unwrap(),
-        DEPLOY => Transaction::with_type(TransactionType::Invoke {
-            address: "0x0493429f345e634ae58eef2a3984540bdaaa37da0105636dd1d0e75898fe7cc0"
-                .to_string(),
-            key: "0x0361458367e696363fbcc70777d07ebbd2394e89fd0adcaf147faccd1d294d60".to_string(),
-            storage: "0x64696e616d6f".to_string(),
-        })
-        .unwrap(),
-        _ => {
-            println!("{error_message}");
-            return;
-        }
-    };
-    let response = send_to_sequencer(vec![tx]).await;
-    println!("{:#?}", response);
-}
-
-async fn send_to_sequencer(txs: Vec<Transaction>) -> Response {
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli).await {
+        Ok(broadcasts) => {
+            let batched = matches!(cli.command, Command::Batch { .. });
+            match cli.output {
+                Output::Human if batched => {
+                    for (i, broadcast) in broadcasts.iter().enumerate() {
+                        println!("Batch {i}\n{broadcast}");
+                    }
+                }
+                Output::Human => println!("{}", broadcasts[0]),
+                Output::Json if batched => {
+                    println!("{}", serde_json::to_string_pretty(&broadcasts).unwrap())
+                }
+                Output::Json => {
+                    println!("{}", serde_json::to_string_pretty(&broadcasts[0]).unwrap())
+                }
+            }
+            if broadcasts.iter().any(Broadcast::is_err) {
+                ExitCode::from(REJECTED_EXIT_CODE)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(e.code())
+        }
+    }
+}
+
+async fn run(cli: &Cli) -> Result<Vec<Broadcast>, Error> {
+    let tendermint_client =
+        HttpClient::new(cli.rpc_url.as_str()).map_err(|_| Error::InvalidArgument {
+            name: "RPC URL",
+            value: cli.rpc_url.clone(),
+        })?;
+    let private_key =
+        FieldElement::from_hex(&cli.private_key).map_err(|_| Error::InvalidArgument {
+            name: "private key",
+            value: cli.private_key.clone(),
+        })?;
+    // Transactions are only valid on the chain their hash commits to
+    let chain_id = match &cli.chain_id {
+        Some(chain_id) => chain_id.clone(),
+        None => tendermint_client
+            .status()
+            .await?
+            .node_info
+            .network
+            .to_string(),
+    };
+    let commands = match &cli.command {
+        Command::Transaction(command) => vec![command.clone()],
+        Command::Batch { file, .. } => read_batch_file(file)?,
+    };
+    let nonce = match &cli.nonce {
+        Some(nonce) => nonce.clone(),
+        // A lone DeployAccount does not need the sender's nonce
+        None if matches!(commands[..], [TransactionCommand::DeployAccount { .. }]) => {
+            "0x0".to_string()
+        }
+        None => next_nonce(&tendermint_client, &cli.account).await?,
+    };
+    let mut nonce = FieldElement::from_hex(&nonce).map_err(|_| Error::InvalidArgument {
+        name: "nonce",
+        value: nonce.clone(),
+    })?;
+
+    let mut txs = Vec::with_capacity(commands.len());
+    for command in &commands {
+        // A new account starts at nonce 0, other transactions use the sender's
+        let tx_nonce = match command {
+            TransactionCommand::DeployAccount { .. } => FieldElement::ZERO,
+            _ => {
+                let tx_nonce = nonce;
+                nonce = nonce + FieldElement::ONE;
+                tx_nonce
+            }
+        };
+        txs.push(build_transaction(
+            command,
+            &cli.account,
+            &private_key,
+            format!("{tx_nonce:#x}"),
+            &chain_id,
+        )?);
+    }
+
+    let (max_txs, max_bytes) = match cli.command {
+        Command::Transaction(_) => (1, usize::MAX),
+        Command::Batch {
+            max_txs, max_bytes, ..
+        } => (max_txs.max(1), max_bytes),
+    };
+    let mut broadcasts = vec![];
+    for batch in split_batches(txs, max_txs, max_bytes) {
+        let broadcast = send_to_sequencer(&tendermint_client, cli.broadcast_mode, batch).await?;
+        // Later batches would fail on the nonces of this one
+        let rejected = broadcast.is_err();
+        broadcasts.push(broadcast);
+        if rejected {
+            break;
+        }
+    }
+    Ok(broadcasts)
+}
+
+/// Commands of a batch file, a JSON array or one JSON value per line.
+fn read_batch_file(path: &str) -> Result<Vec<TransactionCommand>, Error> {
+    let read = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let contents = read.map_err(|source| TransactionError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let parse = |source| Error::BatchFile {
+        path: path.to_string(),
+        source,
+    };
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(&contents).map_err(parse);
+    }
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(parse))
+        .collect()
+}
+
+/// Splits `txs` in order into batches of at most `max_txs` transactions and
+/// `max_bytes` encoded bytes.
+fn split_batches(txs: Vec<Transaction>, max_txs: usize, max_bytes: usize) -> Vec<Vec<Transaction>> {
+    let mut batches = vec![];
+    let mut batch: Vec<Transaction> = vec![];
+    for tx in txs {
+        batch.push(tx);
+        if batch.len() > 1
+            && (batch.len() > max_txs || wire::encode_batch(&batch).len() > max_bytes)
+        {
+            let tx = batch.pop().unwrap();
+            batches.push(std::mem::replace(&mut batch, vec![tx]));
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Signed transaction of the subcommand.
+fn build_transaction(
+    command: &TransactionCommand,
+    account: &str,
+    private_key: &FieldElement,
+    nonce: String,
+    chain_id: &str,
+) -> Result<Transaction, TransactionError> {
+    let tx = match command {
+        TransactionCommand::Declare {
+            class,
+            compiled_class,
+        } => Transaction::with_type(
+            TransactionType::declare(class, compiled_class)?,
+            account.to_string(),
+            nonce,
+            chain_id,
+        ),
+        TransactionCommand::Deploy {
+            class,
+            salt,
+            calldata,
+        } => Transaction::with_type(
+            TransactionType::Deploy {
+                class_hash: format!("{:#x}", class_hash(class)?),
+                contract_address_salt: salt.clone(),
+                constructor_calldata: calldata.clone(),
+                deployer_address: account.to_string(),
+            },
+            account.to_string(),
+            nonce,
+            chain_id,
+        ),
+        TransactionCommand::Invoke {
+            address,
+            key,
+            value,
+        } => Transaction::with_type(
+            TransactionType::Invoke {
+                address: address.clone(),
+                key: key.clone(),
+                storage: value.clone(),
+            },
+            account.to_string(),
+            nonce,
+            chain_id,
+        ),
+        // A new account of the class, holding the same key
+        TransactionCommand::DeployAccount { class, salt } => {
+            let class_hash = class_hash(class)?;
+            let salt = FieldElement::from_hex(salt).map_err(|_| TransactionError::InvalidFelt {
+                field: "contract_address_salt",
+                value: salt.clone(),
+            })?;
+            let public_key = get_public_key(private_key);
+            let address = contract_address(class_hash, salt, &[public_key], FieldElement::ZERO);
+            Transaction::with_type(
+                TransactionType::DeployAccount {
+                    class_hash: format!("{class_hash:#x}"),
+                    contract_address_salt: format!("{salt:#x}"),
+                    constructor_calldata: vec![format!("{public_key:#x}")],
+                },
+                format!("{address:#x}"),
+                nonce,
+                chain_id,
+            )
+        }
+    };
+    tx?.sign(private_key)
+}
+
+/// Next nonce of `address`, counting the transactions in the mempool.
+async fn next_nonce(tendermint_client: &HttpClient, address: &str) -> Result<String, Error> {
+    let response = tendermint_client
+        .abci_query(Some(format!("/nonce/{address}")), vec![], None, false)
+        .await?;
+    if response.code.is_err() {
+        return Ok("0x0".to_string());
+    }
+    Ok(String::from_utf8_lossy(&response.value).into_owned())
+}
+
+async fn send_to_sequencer(
+    tendermint_client: &HttpClient,
+    mode: BroadcastMode,
+    txs: Vec<Transaction>,
+) -> Result<Broadcast, Error> {
     // Check each transaction on tendermint.check call
-    let tx = bincode::serialize(&txs).unwrap();
+    let tx = wire::encode_batch(&txs);
+    let transactions = txs.into_iter().map(|tx| tx.transaction_hash).collect();
 
-    let tendermint_client = HttpClient::new("http://127.0.0.1:26657").unwrap();
-    tendermint_client.broadcast_tx_sync(tx).await.unwrap()
+    let broadcast = match mode {
+        BroadcastMode::Async => {
+            let response = tendermint_client.broadcast_tx_async(tx).await?;
+            Broadcast::checked(response.hash, response.code, response.log, transactions)
+        }
+        BroadcastMode::Sync => {
+            let response = tendermint_client.broadcast_tx_sync(tx).await?;
+            Broadcast::checked(response.hash, response.code, response.log, transactions)
+        }
+        BroadcastMode::Commit => {
+            let response = tendermint_client.broadcast_tx_commit(tx).await?;
+            Broadcast {
+                tx_hash: response.hash.to_string(),
+                transactions,
+                code: response.check_tx.code.value(),
+                log: response.check_tx.log,
+                height: Some(response.height.value()),
+                deliver_code: Some(response.tx_result.code.value()),
+                deliver_log: Some(response.tx_result.log),
+            }
+        }
+    };
+    Ok(broadcast)
 }
@@ -1,9 +1,23 @@
+use clap::Parser;
+use consensus_app::application::AbciVersion;
 use consensus_app::blockchain::BlockchainApp;
 use consensus_app::server::ServerBuilder;
+use consensus_app::snapshot::SnapshotConfig;
+use consensus_app::storage::FileStorage;
 use tracing_subscriber::filter::LevelFilter;
 
+#[derive(Parser)]
+#[command(about = "ABCI server of the Starknet chain")]
+struct Cli {
+    /// ABCI version of the node, 0.37 for Tendermint or 0.38 for CometBFT
+    #[arg(long, default_value = "0.37")]
+    abci_version: AbciVersion,
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     tracing_subscriber::fmt()
         .with_max_level(LevelFilter::INFO)
         .init();
@@ -11,9 +25,18 @@ async fn main() {
     let host = "127.0.0.1";
     let port = "26658";
     let read_buf_size = 1048576;
+    let storage_path = "./db/blocks.log";
+    let snapshot_interval = 100;
 
-    let app = BlockchainApp::new();
+    let storage = FileStorage::open(storage_path).unwrap();
+    let app = BlockchainApp::with_storage(storage)
+        .unwrap()
+        .with_snapshots(SnapshotConfig {
+            interval: snapshot_interval,
+            ..Default::default()
+        });
     let server = ServerBuilder::new(read_buf_size)
+        .abci_version(cli.abci_version)
         .bind(format!("{}:{}", host, port), app)
         .await
         .unwrap();
@@ -1,47 +1,471 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
 
-use tendermint_abci::Application;
-use tendermint_proto::abci::{
-    RequestBeginBlock, RequestCheckTx, RequestDeliverTx, RequestEndBlock, RequestInfo,
-    RequestInitChain, RequestQuery, ResponseBeginBlock, ResponseCheckTx, ResponseCommit,
-    ResponseDeliverTx, ResponseEndBlock, ResponseInfo, ResponseInitChain, ResponseQuery,
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use starknet_crypto::Felt as FieldElement;
+use tendermint_proto::v0_37::abci::{
+    response_apply_snapshot_chunk, response_offer_snapshot, response_process_proposal,
+    RequestApplySnapshotChunk, RequestBeginBlock, RequestCheckTx, RequestDeliverTx,
+    RequestEndBlock, RequestInfo, RequestInitChain, RequestLoadSnapshotChunk, RequestOfferSnapshot,
+    RequestPrepareProposal, RequestProcessProposal, RequestQuery, ResponseApplySnapshotChunk,
+    ResponseBeginBlock, ResponseCheckTx, ResponseCommit, ResponseDeliverTx, ResponseEndBlock,
+    ResponseInfo, ResponseInitChain, ResponseListSnapshots, ResponseLoadSnapshotChunk,
+    ResponseOfferSnapshot, ResponsePrepareProposal, ResponseProcessProposal, ResponseQuery,
+    Snapshot,
 };
-use tracing::info;
+use tendermint_proto::v0_37::crypto::{ProofOp, ProofOps};
+use tendermint_proto::v0_38::abci::{
+    response_verify_vote_extension, RequestExtendVote, RequestFinalizeBlock,
+    RequestVerifyVoteExtension, ResponseExtendVote, ResponseFinalizeBlock,
+    ResponseVerifyVoteExtension,
+};
+use tracing::{error, info, warn};
+
+use crate::application::{convert, Application};
+use crate::execution::{CommittedState, Executor, PendingState, PendingWrites};
+use crate::genesis::Genesis;
+use crate::proof::GetProofResult;
+use crate::proposal::{self, ProposalConfig};
+use crate::query::{Query, QueryError, QUERY_CODESPACE};
+use crate::snapshot::{Restore, SnapshotConfig, SnapshotError, Snapshots, SNAPSHOT_FORMAT};
+use crate::state::{State, StateCommitment};
+use crate::storage::{MemoryStorage, Storage, StoredBlock};
+use crate::transaction::{chain_id_felt, Transaction};
+use crate::validation::{decode_batch, TxError, TX_CODESPACE};
+
+/// Type of the `ProofOp` carrying a JSON encoded `GetProofResult`.
+pub const STARKNET_PROOF_OP: &str = "starknet:get_proof";
+
+pub type Address = String;
+pub type Key = String;
+pub type StorageValue = String;
+pub type Contract = (Key, StorageValue);
+
+/// A transaction included in a block, with the storage it wrote.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockTransaction {
+    pub transaction: Transaction,
+    pub writes: HashMap<Address, Contract>,
+}
+
+/// Block N of the app corresponds to Tendermint block N.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Block {
+    pub chain_id: String,
+    pub height: i64,
+    /// Unix time in seconds from the Tendermint header.
+    pub timestamp: i64,
+    pub proposer_address: Vec<u8>,
+    /// Transactions in execution order.
+    pub transactions: Vec<BlockTransaction>,
+    /// State after executing the block, set in EndBlock.
+    pub commitment: StateCommitment,
+}
+
+impl Block {
+    fn new(height: i64) -> Self {
+        Self {
+            chain_id: String::new(),
+            height,
+            timestamp: 0,
+            proposer_address: vec![],
+            transactions: vec![],
+            commitment: StateCommitment::default(),
+        }
+    }
 
-use crate::transaction::{Transaction, TransactionType};
+    fn transaction(&self, hash: &str) -> Option<&BlockTransaction> {
+        self.transactions
+            .iter()
+            .find(|included| included.transaction.transaction_hash == hash)
+    }
+}
 
-pub const MAX_VARINT_LENGTH: usize = 16;
+/// Chain as of the last Commit, the view served to Query and CheckTx.
+#[derive(Default)]
+struct CommittedChain {
+    chain_id: String,
+    height: i64,
+    app_hash: Vec<u8>,
+    commitment: StateCommitment,
+    blocks: Vec<Block>,
+    // Height of every committed transaction by hash
+    transactions: HashMap<String, i64>,
+}
 
-type StateRoot = String;
-type ContractHash = String;
-type Address = String;
-type Key = String;
-type Storage = String;
-type Proof = String;
-type Contract = (Key, Storage, Proof);
-type ContractStore = HashMap<ContractHash, HashMap<Address, Contract>>;
-type Block = (ContractStore, StateRoot);
+impl CommittedChain {
+    fn push(&mut self, block: Block) {
+        self.chain_id.clone_from(&block.chain_id);
+        self.height = block.height;
+        self.app_hash = block.commitment.app_hash();
+        self.commitment = block.commitment;
+        for included in &block.transactions {
+            let hash = included.transaction.transaction_hash.clone();
+            self.transactions.insert(hash, block.height);
+        }
+        self.blocks.push(block);
+    }
 
+    fn block(&self, height: i64) -> Option<&Block> {
+        let first = self.blocks.first()?.height;
+        let index = usize::try_from(height.checked_sub(first)?).ok()?;
+        self.blocks.get(index)
+    }
+
+    /// State after block `height`, the latest state for height 0 which also
+    /// covers genesis before the first block.
+    fn commitment_at(&self, height: i64) -> Result<&StateCommitment, QueryError> {
+        match height {
+            0 => Ok(&self.commitment),
+            height => self
+                .block(height)
+                .map(|block| &block.commitment)
+                .ok_or(QueryError::UnknownHeight(height)),
+        }
+    }
+}
+
+/// Transactions accepted by CheckTx and not committed yet.
+#[derive(Default)]
+struct Mempool {
+    transactions: HashSet<String>,
+    writes: PendingWrites,
+}
+
+/// Block opened by BeginBlock and sealed by Commit, invisible to queries until
+/// then.
+#[derive(Default)]
+struct PendingChain {
+    block: Option<Block>,
+}
+
+/// Clones share the same state, so every ABCI connection sees the same chain.
+///
+/// Trie nodes are content-addressed, so DeliverTx writing into `state` never
+/// changes what is readable at the committed roots. Locks are always taken in
+/// the order `pending`, `state`, `committed`, `mempool`, `storage`,
+/// `snapshots`.
 #[derive(Clone)]
 pub struct BlockchainApp {
-    app_hash: Vec<u8>,
-    blocks: RefCell<Vec<Block>>,
+    pending: Arc<Mutex<PendingChain>>,
+    state: Arc<RwLock<State>>,
+    committed: Arc<RwLock<CommittedChain>>,
+    // Hashes of transactions accepted by CheckTx and not committed yet
+    mempool: Arc<Mutex<Mempool>>,
+    storage: Arc<Mutex<dyn Storage>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    proposal: ProposalConfig,
 }
 
 impl BlockchainApp {
     pub fn new() -> Self {
-        Self {
-            app_hash: vec![0_u8; MAX_VARINT_LENGTH],
-            blocks: RefCell::new(vec![]),
+        Self::with_storage(MemoryStorage::new()).expect("MemoryStorage: infallible load")
+    }
+
+    /// Restores the chain persisted in `storage` and persists every further
+    /// commit to it.
+    pub fn with_storage(storage: impl Storage + 'static) -> io::Result<Self> {
+        let mut state = State::new();
+        let mut committed = CommittedChain::default();
+        for stored in storage.load()? {
+            state.load_changes(stored.changes);
+            committed.push(stored.block);
         }
+        info!("Loaded chain up to height {}", committed.height);
+
+        Ok(Self {
+            pending: Default::default(),
+            state: Arc::new(RwLock::new(state)),
+            committed: Arc::new(RwLock::new(committed)),
+            mempool: Default::default(),
+            storage: Arc::new(Mutex::new(storage)),
+            snapshots: Default::default(),
+            proposal: ProposalConfig::default(),
+        })
+    }
+
+    /// Limits of the blocks this node proposes and accepts from proposers.
+    pub fn with_proposal_config(mut self, config: ProposalConfig) -> Self {
+        self.proposal = config;
+        self
+    }
+
+    /// Takes state sync snapshots of committed blocks as set by `config`,
+    /// which are kept in memory only.
+    pub fn with_snapshots(mut self, config: SnapshotConfig) -> Self {
+        self.snapshots = Arc::new(Mutex::new(Snapshots::new(config)));
+        self
+    }
+
+    /// Replaces the empty chain with the state of a restored snapshot.
+    fn restore_snapshot(&self, restore: Restore) -> Result<(), SnapshotError> {
+        let height = restore.height;
+        let (restored, stored) = restore.finish()?;
+        let mut state = self.state.write().unwrap();
+        let mut committed = self.committed.write().unwrap();
+        self.storage
+            .lock()
+            .unwrap()
+            .append(&stored)
+            .expect("Failed to persist restored snapshot");
+        *state = restored;
+        *committed = CommittedChain::default();
+        committed.push(stored.block);
+        info!("Restored snapshot of height {height}");
+        Ok(())
+    }
+
+    /// Opens `block`, filled by `execute` and sealed by `end` under both ABCI
+    /// versions.
+    fn begin(&self, block: Block) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(block) = pending.block.as_ref() {
+            error!("Block {} was never committed, discarding it", block.height);
+        }
+        pending.block = Some(block);
+    }
+
+    /// Executes a batch in the open block.
+    fn execute(&self, tx: &[u8]) -> ResponseDeliverTx {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(block) = pending.block.as_mut() else {
+            return deliver_tx_error(TxError::OutsideBlock, None);
+        };
+        let txs = match decode_batch(tx) {
+            Ok(txs) => txs,
+            Err(e) => return deliver_tx_error(e, None),
+        };
+        let mut state = self.state.write().unwrap();
+        let committed = self.committed.read().unwrap();
+
+        // The batch is applied as a whole or not at all
+        let mut executor = Executor::new(&*state, chain_id_felt(&block.chain_id));
+        let mut executed = Vec::with_capacity(txs.len());
+        for (index, tx) in txs.iter().enumerate() {
+            let hash = &tx.transaction_hash;
+            if committed.transactions.contains_key(hash) || block.transaction(hash).is_some() {
+                return deliver_tx_error(TxError::DuplicateTransaction(hash.clone()), Some(index));
+            }
+            match executor.execute(index, tx) {
+                Ok(tx) => executed.push(tx),
+                Err(e) => return deliver_tx_error(e, Some(index)),
+            }
+        }
+        let diff = executor.into_diff();
+        diff.apply(&mut state);
+
+        let mut events = Vec::with_capacity(executed.len());
+        for (transaction, tx) in txs.into_iter().zip(executed) {
+            block.transactions.push(BlockTransaction {
+                transaction,
+                writes: tx.writes,
+            });
+            events.push(tx.event);
+        }
+        ResponseDeliverTx {
+            code: 0,
+            events,
+            ..Default::default()
+        }
+    }
+
+    /// Seals the state of the open block and returns its commitment.
+    fn end(&self, height: i64) -> StateCommitment {
+        let mut pending = self.pending.lock().unwrap();
+        let commitment = self.state.write().unwrap().commit();
+        match pending.block.as_mut() {
+            Some(block) if block.height == height => block.commitment = commitment,
+            _ => error!("EndBlock for height {} without a BeginBlock", height),
+        }
+        commitment
+    }
+
+    /// Validates a batch against the committed state and the mempool, and
+    /// adds it to the mempool if it is valid.
+    ///
+    /// Hashes and writes of the mempool are dropped on Commit and rebuilt by
+    /// the rechecks of the remaining transactions, which Tendermint runs in
+    /// mempool order.
+    fn check_batch(&self, txs: &[Transaction]) -> Result<(), TxError> {
+        let state = self.state.read().unwrap();
+        let committed = self.committed.read().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        let committed_state = CommittedState {
+            state: &state,
+            commitment: &committed.commitment,
+        };
+        let view = PendingState {
+            base: &committed_state,
+            writes: &mempool.writes,
+        };
+
+        let mut executor = Executor::new(&view, chain_id_felt(&committed.chain_id));
+        for (index, tx) in txs.iter().enumerate() {
+            let hash = &tx.transaction_hash;
+            if committed.transactions.contains_key(hash) || mempool.transactions.contains(hash) {
+                return Err(TxError::DuplicateTransaction(hash.clone()));
+            }
+            executor.execute(index, tx)?;
+        }
+        let diff = executor.into_diff();
+        mempool.writes.extend(diff);
+        mempool
+            .transactions
+            .extend(txs.iter().map(|tx| tx.transaction_hash.clone()));
+        Ok(())
+    }
+
+    pub fn generate_proof(
+        &self,
+        height: i64,
+        address: &FieldElement,
+        keys: &[FieldElement],
+    ) -> Option<GetProofResult> {
+        let state = self.state.read().unwrap();
+        let committed = self.committed.read().unwrap();
+        let block = committed.block(height)?;
+        Some(state.proof(&block.commitment, address, keys))
+    }
+
+    fn handle_query(&self, request: &RequestQuery) -> Result<ResponseQuery, QueryError> {
+        let query: Query = request.path.parse()?;
+        let state = self.state.read().unwrap();
+        let committed = self.committed.read().unwrap();
+
+        // Height 0 reads the latest block
+        let height = match request.height {
+            0 => committed.height,
+            height => height,
+        };
+
+        let (value, proof) = match query {
+            Query::Storage { address, key } => {
+                let commitment = committed.commitment_at(request.height)?;
+                let value = state
+                    .storage_at(commitment, &address, &key)
+                    .unwrap_or(FieldElement::ZERO);
+                let proof = request
+                    .prove
+                    .then(|| state.proof(commitment, &address, &[key]));
+                (format!("0x{:x}", value), proof)
+            }
+            Query::Proof { address, key } => {
+                let commitment = committed.commitment_at(request.height)?;
+                let proof = state.proof(commitment, &address, &[key]);
+                let value = serde_json::to_string(&proof).expect("GetProofResult: serializable");
+                (value, request.prove.then_some(proof))
+            }
+            Query::Nonce { address } => {
+                let commitment = committed.commitment_at(request.height)?;
+                let contract = state
+                    .contract_at(commitment, &address)
+                    .ok_or_else(|| QueryError::NotFound(format!("contract 0x{:x}", address)))?;
+                let pending = match request.height {
+                    0 => self
+                        .mempool
+                        .lock()
+                        .unwrap()
+                        .writes
+                        .nonces
+                        .get(&address)
+                        .copied(),
+                    _ => None,
+                };
+                (format!("0x{:x}", pending.unwrap_or(contract.nonce)), None)
+            }
+            Query::Class { class_hash } => {
+                let commitment = committed.commitment_at(request.height)?;
+                let class = state
+                    .declared_class_at(commitment, &class_hash)
+                    .ok_or_else(|| QueryError::NotFound(format!("class 0x{:x}", class_hash)))?;
+                let contract_class: serde_json::Value = serde_json::from_str(&class.contract_class)
+                    .map_err(|e| {
+                        QueryError::InvalidState(format!("class 0x{:x}: {}", class_hash, e))
+                    })?;
+                let class = json!({
+                    "class_hash": format!("0x{:x}", class.class_hash),
+                    "compiled_class_hash": format!("0x{:x}", class.compiled_class_hash),
+                    "contract_class": contract_class,
+                });
+                (class.to_string(), None)
+            }
+            Query::Block { height } => {
+                let block = committed
+                    .block(height)
+                    .ok_or(QueryError::UnknownHeight(height))?;
+                let transactions: Vec<_> = block
+                    .transactions
+                    .iter()
+                    .map(|included| &included.transaction.transaction_hash)
+                    .collect();
+                let block = json!({
+                    "block_number": block.height,
+                    "timestamp": block.timestamp,
+                    "proposer_address": to_hex(&block.proposer_address),
+                    "state_root": format!("0x{:x}", block.commitment.state_root),
+                    "contracts_root": format!("0x{:x}", block.commitment.contracts_root),
+                    "classes_root": format!("0x{:x}", block.commitment.classes_root),
+                    "transactions": transactions,
+                });
+                (block.to_string(), None)
+            }
+            Query::Transaction { hash } => {
+                // Transactions are indexed by their canonical hash
+                let hash = format!("{hash:#x}");
+                let (block_number, included) = committed
+                    .transactions
+                    .get(&hash)
+                    .and_then(|height| committed.block(*height))
+                    .and_then(|block| Some((block.height, block.transaction(&hash)?)))
+                    .ok_or_else(|| QueryError::NotFound(format!("transaction {hash}")))?;
+                let tx = json!({
+                    "block_number": block_number,
+                    "transaction_hash": hash,
+                    "transaction": included.transaction,
+                    "contracts": included.writes,
+                });
+                (tx.to_string(), None)
+            }
+        };
+
+        Ok(ResponseQuery {
+            code: 0,
+            key: request.path.clone().into_bytes().into(),
+            value: value.into_bytes().into(),
+            proof_ops: proof.map(|proof| ProofOps {
+                ops: vec![ProofOp {
+                    r#type: STARKNET_PROOF_OP.to_string(),
+                    key: request.path.clone().into_bytes().into(),
+                    data: serde_json::to_vec(&proof)
+                        .expect("GetProofResult: serializable")
+                        .into(),
+                }],
+            }),
+            height,
+            ..Default::default()
+        })
     }
 }
 
 impl Application for BlockchainApp {
-    fn init_chain(&self, _request: RequestInitChain) -> ResponseInitChain {
-        let mut blocks = self.blocks.borrow_mut();
-        blocks.push((HashMap::new(), "0x0".to_string()));
-        Default::default()
+    fn init_chain(&self, request: RequestInitChain) -> ResponseInitChain {
+        let mut state = self.state.write().unwrap();
+        // Tendermint cannot be told that the genesis is unusable
+        let genesis = Genesis::from_app_state(&request.app_state_bytes)
+            .expect("app_state of the genesis: invalid JSON");
+        genesis
+            .apply(&mut state)
+            .expect("app_state of the genesis: invalid account");
+        let commitment = state.commit();
+        let mut committed = self.committed.write().unwrap();
+        committed.chain_id = request.chain_id;
+        committed.commitment = commitment;
+        ResponseInitChain {
+            app_hash: commitment.app_hash().into(),
+            ..Default::default()
+        }
     }
 
     fn info(&self, request: RequestInfo) -> ResponseInfo {
@@ -50,77 +474,329 @@ impl Application for BlockchainApp {
             request.version, request.block_version, request.p2p_version
         );
 
+        let committed = self.committed.read().unwrap();
         ResponseInfo {
             data: "blockchain-rs".to_string(),
             version: "0.1.0".to_string(),
             app_version: 1,
-            last_block_height: self.blocks.borrow().len() as i64,
-            last_block_app_hash: self.app_hash.clone().into(),
+            last_block_height: committed.height,
+            last_block_app_hash: committed.app_hash.clone().into(),
         }
     }
 
-    fn query(&self, _request: RequestQuery) -> ResponseQuery {
-        Default::default()
+    fn query(&self, request: RequestQuery) -> ResponseQuery {
+        match self.handle_query(&request) {
+            Ok(response) => response,
+            Err(e) => ResponseQuery {
+                code: e.code(),
+                log: e.to_string(),
+                height: request.height,
+                codespace: QUERY_CODESPACE.to_string(),
+                ..Default::default()
+            },
+        }
     }
 
     fn check_tx(&self, request: RequestCheckTx) -> ResponseCheckTx {
-        let _tx: Vec<Transaction> = bincode::deserialize(&request.tx).unwrap();
-        ResponseCheckTx {
-            code: 0,
-            ..Default::default()
+        let result = decode_batch(&request.tx).and_then(|txs| self.check_batch(&txs));
+        match result {
+            Ok(()) => ResponseCheckTx {
+                code: 0,
+                ..Default::default()
+            },
+            Err(e) => {
+                // A batch failing its recheck is not added back after Commit
+                info!("Rejected transaction batch: {}", e);
+                ResponseCheckTx {
+                    code: e.code(),
+                    log: e.to_string(),
+                    codespace: TX_CODESPACE.to_string(),
+                    ..Default::default()
+                }
+            }
         }
     }
 
-    fn begin_block(&self, _request: RequestBeginBlock) -> ResponseBeginBlock {
+    fn begin_block(&self, request: RequestBeginBlock) -> ResponseBeginBlock {
+        let header = request.header.unwrap_or_default();
+        self.begin(Block {
+            chain_id: header.chain_id,
+            timestamp: header.time.map(|time| time.seconds).unwrap_or_default(),
+            proposer_address: header.proposer_address.to_vec(),
+            ..Block::new(header.height)
+        });
         Default::default()
     }
 
     fn deliver_tx(&self, request: RequestDeliverTx) -> ResponseDeliverTx {
-        let txs: Vec<Transaction> = bincode::deserialize(&request.tx).unwrap();
-        let tx = txs[0].clone();
-        match tx.transaction_type {
-            TransactionType::Declare { .. } => {
-                let mut blocks = self.blocks.borrow_mut();
-                let mut new_block = (HashMap::new(), "0x0".to_string());
-                new_block.0.insert(tx.transaction_hash, HashMap::new());
-                blocks.push(new_block);
-            }
-            TransactionType::Invoke {
-                address,
-                key,
-                storage,
-            } => {
-                let mut blocks = self.blocks.borrow_mut();
-                let new_block = (
-                    HashMap::from([(
-                        tx.transaction_hash,
-                        HashMap::from([(address, (key, storage, generate_proof()))]),
-                    )]),
-                    "0x06cbb5937c087bdece6ffe0a76300e097f082625ad407da1e62afb2f48bed6e7"
-                        .to_string(),
-                );
-                blocks.push(new_block);
-            }
-            TransactionType::DeployAccount { .. } => {}
-        }
-        ResponseDeliverTx {
-            code: 0,
-            ..Default::default()
-        }
+        self.execute(&request.tx)
     }
 
-    fn end_block(&self, _request: RequestEndBlock) -> ResponseEndBlock {
+    fn end_block(&self, request: RequestEndBlock) -> ResponseEndBlock {
+        self.end(request.height);
         Default::default()
     }
 
     fn commit(&self) -> ResponseCommit {
+        let mut pending = self.pending.lock().unwrap();
+        let mut state = self.state.write().unwrap();
+        let mut committed = self.committed.write().unwrap();
+
+        let mut block = pending.block.take().unwrap_or_else(|| Block {
+            chain_id: committed.chain_id.clone(),
+            ..Block::new(committed.height + 1)
+        });
+        block.commitment = state.commit();
+        let stored = StoredBlock {
+            block,
+            changes: state.take_changes(),
+        };
+        self.storage
+            .lock()
+            .unwrap()
+            .append(&stored)
+            .expect("Failed to persist committed block");
+
+        // Whatever the block included, failed or Tendermint evicted is gone
+        // from the mempool, the rechecks add back the batches that remain
+        let mut mempool = self.mempool.lock().unwrap();
+        mempool.transactions.clear();
+        mempool.writes = PendingWrites::default();
+        info!(
+            "Committed height {} with app hash 0x{:x}",
+            stored.block.height, stored.block.commitment.state_root
+        );
+
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if snapshots.is_due(stored.block.height) {
+            match state.snapshot(&stored.block.commitment) {
+                Some(changes) => snapshots.take(&StoredBlock {
+                    block: stored.block.clone(),
+                    changes,
+                }),
+                None => error!("State of height {} is incomplete", stored.block.height),
+            }
+        }
+        committed.push(stored.block);
         ResponseCommit {
+            data: committed.app_hash.clone().into(),
             retain_height: 0,
+        }
+    }
+
+    fn prepare_proposal(&self, request: RequestPrepareProposal) -> ResponsePrepareProposal {
+        let state = self.state.read().unwrap();
+        let committed = self.committed.read().unwrap();
+        // The proposed block follows the last committed one
+        let base = CommittedState {
+            state: &state,
+            commitment: &committed.commitment,
+        };
+        let txs = proposal::prepare(
+            &base,
+            chain_id_felt(&committed.chain_id),
+            |hash| committed.transactions.contains_key(hash),
+            &request.txs,
+            usize::try_from(request.max_tx_bytes).unwrap_or(0),
+            &self.proposal,
+        );
+        ResponsePrepareProposal {
+            txs: txs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn process_proposal(&self, request: RequestProcessProposal) -> ResponseProcessProposal {
+        let state = self.state.read().unwrap();
+        let committed = self.committed.read().unwrap();
+        let base = CommittedState {
+            state: &state,
+            commitment: &committed.commitment,
+        };
+        let result = proposal::process(
+            &base,
+            chain_id_felt(&committed.chain_id),
+            |hash| committed.transactions.contains_key(hash),
+            &request.txs,
+            &self.proposal,
+        );
+        let status = match result {
+            Ok(()) => response_process_proposal::ProposalStatus::Accept,
+            Err(e) => {
+                warn!("Rejected proposal of height {}: {}", request.height, e);
+                response_process_proposal::ProposalStatus::Reject
+            }
+        };
+        ResponseProcessProposal {
+            status: status as i32,
+        }
+    }
+
+    // The app does not extend votes, so only empty extensions are valid
+    fn extend_vote(&self, _request: RequestExtendVote) -> ResponseExtendVote {
+        Default::default()
+    }
+
+    fn verify_vote_extension(
+        &self,
+        request: RequestVerifyVoteExtension,
+    ) -> ResponseVerifyVoteExtension {
+        let status = if request.vote_extension.is_empty() {
+            response_verify_vote_extension::VerifyStatus::Accept
+        } else {
+            response_verify_vote_extension::VerifyStatus::Reject
+        };
+        ResponseVerifyVoteExtension {
+            status: status as i32,
+        }
+    }
+
+    fn finalize_block(&self, request: RequestFinalizeBlock) -> ResponseFinalizeBlock {
+        // FinalizeBlock carries no header, the chain id is set by InitChain
+        let chain_id = self.committed.read().unwrap().chain_id.clone();
+        self.begin(Block {
+            chain_id,
+            timestamp: request.time.map(|time| time.seconds).unwrap_or_default(),
+            proposer_address: request.proposer_address.to_vec(),
+            ..Block::new(request.height)
+        });
+        let tx_results = request
+            .txs
+            .iter()
+            .map(|tx| convert(self.execute(tx)))
+            .collect();
+        let commitment = self.end(request.height);
+        ResponseFinalizeBlock {
+            tx_results,
+            app_hash: commitment.app_hash().into(),
+            ..Default::default()
+        }
+    }
+
+    fn list_snapshots(&self) -> ResponseListSnapshots {
+        let snapshots = self.snapshots.lock().unwrap();
+        ResponseListSnapshots {
+            snapshots: snapshots
+                .list()
+                .map(|snapshot| Snapshot {
+                    height: snapshot.height,
+                    format: SNAPSHOT_FORMAT,
+                    chunks: snapshot.chunk_count(),
+                    hash: snapshot.hash.to_bytes_be().to_vec().into(),
+                    metadata: snapshot.metadata().into(),
+                })
+                .collect(),
+        }
+    }
+
+    fn offer_snapshot(&self, request: RequestOfferSnapshot) -> ResponseOfferSnapshot {
+        use response_offer_snapshot::Result;
+
+        let Some(snapshot) = request.snapshot else {
+            return ResponseOfferSnapshot {
+                result: Result::Reject as i32,
+            };
+        };
+        // Snapshots only bootstrap a node without any block
+        if self.committed.read().unwrap().height > 0 {
+            return ResponseOfferSnapshot {
+                result: Result::Abort as i32,
+            };
+        }
+        let result = match Restore::new(
+            snapshot.height,
+            snapshot.format,
+            &snapshot.hash,
+            &snapshot.metadata,
+            request.app_hash.to_vec(),
+        ) {
+            Ok(restore) => {
+                self.snapshots.lock().unwrap().restore = Some(restore);
+                Result::Accept
+            }
+            Err(e) => {
+                warn!("Rejected snapshot of height {}: {}", snapshot.height, e);
+                match e {
+                    SnapshotError::UnknownFormat(_) => Result::RejectFormat,
+                    _ => Result::Reject,
+                }
+            }
+        };
+        ResponseOfferSnapshot {
+            result: result as i32,
+        }
+    }
+
+    fn load_snapshot_chunk(&self, request: RequestLoadSnapshotChunk) -> ResponseLoadSnapshotChunk {
+        let snapshots = self.snapshots.lock().unwrap();
+        let chunk = match request.format {
+            SNAPSHOT_FORMAT => snapshots
+                .get(request.height)
+                .and_then(|snapshot| snapshot.chunk(request.chunk)),
+            _ => None,
+        };
+        ResponseLoadSnapshotChunk {
+            chunk: chunk.unwrap_or_default().to_vec().into(),
+        }
+    }
+
+    fn apply_snapshot_chunk(
+        &self,
+        request: RequestApplySnapshotChunk,
+    ) -> ResponseApplySnapshotChunk {
+        use response_apply_snapshot_chunk::Result;
+
+        let response = |result: Result| ResponseApplySnapshotChunk {
+            result: result as i32,
             ..Default::default()
+        };
+        let restore = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            let Some(restore) = snapshots.restore.as_mut() else {
+                return response(Result::Abort);
+            };
+            match restore.apply(request.index, request.chunk.to_vec()) {
+                Ok(false) => return response(Result::Accept),
+                Ok(true) => snapshots.restore.take().unwrap(),
+                // Fetch the chunk again from another peer
+                Err(e @ SnapshotError::ChunkHashMismatch(_)) => {
+                    warn!("Rejected chunk from {}: {}", request.sender, e);
+                    return ResponseApplySnapshotChunk {
+                        result: Result::Retry as i32,
+                        refetch_chunks: vec![request.index],
+                        reject_senders: vec![request.sender],
+                    };
+                }
+                Err(e) => {
+                    warn!("Rejected snapshot: {}", e);
+                    snapshots.restore = None;
+                    return response(Result::RejectSnapshot);
+                }
+            }
+        };
+        match self.restore_snapshot(restore) {
+            Ok(()) => response(Result::Accept),
+            Err(e) => {
+                error!("Failed to restore snapshot: {}", e);
+                response(Result::RejectSnapshot)
+            }
         }
     }
 }
 
-fn generate_proof() -> String {
-    r#"{"class_commitment":"0x700b2c54892319e6a0878ec3e2720753e0da8697963416f9f57bbf2f5d0fbb5","contract_data":{"class_hash":"0x6a367688d5c5a34af83593a5e9f0636ca6ad27ce5a44a9e471af5ea5e5e8a44","contract_state_hash_version":"0x0","nonce":"0x0","root":"0x3b5163e8e2d62ba3da127027979073ab4c56c8078511b355bd00bbd346f3525","storage_proofs":[[{"edge":{"child":"0x64696e616d6f","path":{"len":251,"value":"0x361458367e696363fbcc70777d07ebbd2394e89fd0adcaf147faccd1d294d60"}}}]]},"contract_proof":[{"binary":{"left":"0x2afa9f6d75e9a5c11abda05b7aee195f356e4c4150faa245f0866819b603961","right":"0x451ea2f4c703daa2280c15095b9a09a350e231712adf933228a9f82d6ef48ea"}},{"binary":{"left":"0x2eebb885385342add37257e451c45abebcb7911f018718f6a90b1acd452363c","right":"0x28a072ef2ef16f9cba087f8e13520b1bf7e21e7c43664c8e81ed8c85c375be"}},{"binary":{"left":"0x794a64bb23bcf72a272c3dc20c2dd6ed1bfc5b1aedb4d4e8e954442d2629347","right":"0x79015f3e1a5a818c150533a2ffb1efbfe8fcc07e57311e6626e1715f8bab697"}},{"binary":{"left":"0x2b06c6a7c5e5f1f434755ab8808c854e6f20d31aadb4829b700bee1f9261450","right":"0x507b64fefbf1b7cf4aa0a4beeadf6ea7fae2682d905521743f8fa63514c3f44"}},{"binary":{"left":"0x49bf7c6b17688ad7f2d163d314cfbcc20f4d366fd55737fc5f6f62ed81d6d8c","right":"0x1e0c1c17927ac6a1bf7130d12400757ac433036e07e677994e872a46e16069d"}},{"binary":{"left":"0x57db20687500842637a3d6fb33476ccd0b0f999b89d341f3fc9016e11898ed9","right":"0x697a4087d5836b43827948e949ed014b2e8b5bfb04b03001026f132a0d36381"}},{"binary":{"left":"0x6631759c4c0cf5c5beeedaf9878f9698ab58f56bec475264f084c826f3336e8","right":"0x5c0d033de8583781adb26aad989f6e9b29d92c791184db664a902557225b6a0"}},{"binary":{"left":"0x4f97c8aab7bf692a52521c8309dc0a7527ac96c850a5a8ffdb1009688ec998","right":"0x55af0ec87da52141a4c58a3d88985cd7028cf0f665005949d6684dabf7b5e4f"}},{"binary":{"left":"0x4b299827919097395dab6ccdf866497779f8477260e6792fcd47a182dfd65ba","right":"0x22468df9067ddd71ab88b981e4f05cd7565ec77d5ad4217ba6fdeca184a2f9b"}},{"binary":{"left":"0x77ad35554571199b5b58245d02f93312d8816887dc6c13688e3a2d504127435","right":"0x473c94dc5723cb4ef3ffbd71a536a39b977f86942054feab33d8e72d579f6e8"}},{"binary":{"left":"0x3fd1253dc24917b7901880bcf5cb82c14944a2c3c200b8be51be230a7d4c291","right":"0x3de2805b048d6c1bb06ae523f2ece570ec2cfa65ee71822b9a2b009d309300"}},{"binary":{"left":"0x605f0c03c4fdd9a0ff1e9f839089c75afa9d32320a32d063872c08d5a7d2cc2","right":"0x4bea65865884fa3b8ff8b051443509312847cad668d66d7fd76d9bb25b546e2"}},{"binary":{"left":"0x6ebd8c31d83108fdf6b69efd9125e77e5df0c83f582735b29c1e7ffb67b0f0b","right":"0x28099f6f90265960ffecdedd84c686b2ceb394275939f18aa00ca5ae2372d1c"}},{"binary":{"left":"0x66b9a7d6353280d187457c994cc82017ab73626f60f0fbce669c52a4c06ed4f","right":"0x1b1ef19db7c744d15ac30398737d3ce0158851b2710f41d5747111ba690cbe"}},{"binary":{"left":"0x7933cc047b768c1b23494bda18a3401d608c8d2da158b4d7e26ce544b60a0e0","right":"0x1baeae0108ae6e8211133dadb76ac57d49e81ccd5a6827cf046bc62893ff81b"}},{"binary":{"left":"0xc26e502f49fc7fa0f0a420a0513c4941a34f44f5400bbb0c19af51cd72dc59","right":"0x7d1036213f489baf7c0d831e5b22f4034905d764dff6f9733191f9802e7c0ed"}},{"binary":{"left":"0x26cec760304095968522e342819d2ffe3cf7dfc773c2a204e8f6603be250a93","right":"0x6a087ebb1c7b777bec9170d1b49081bbfcc23c4173fb39135039fc442b4a874"}},{"binary":{"left":"0x4cc9bb3e1beabee0a36f58d2069400bfcf54c4cf6df5c07ab78558ad2df92f","right":"0x412b36b70c0bf91b25641b12b3d4b06f2726f4c1421bfce3bc4241c38bba0ac"}},{"edge":{"child":"0xc3bbdbd75b244229ded425b3b2272991c2bd41572947a622b20d4cea51f478","path":{"len":233,"value":"0x9f345e634ae58eef2a3984540bdaaa37da0105636dd1d0e75898fe7cc0"}}}],"state_commitment":"0x391f30b5ba86364451d6e056c5d9427cc2204f99236a4b2a0f14ec237d11f90"}"#.to_string()
+/// Rejects the whole batch, `index` is the position of the failing transaction.
+fn deliver_tx_error(e: TxError, index: Option<usize>) -> ResponseDeliverTx {
+    let log = match index {
+        Some(index) => format!("Transaction {index} of the batch failed: {e}"),
+        None => e.to_string(),
+    };
+    ResponseDeliverTx {
+        code: e.code(),
+        log,
+        codespace: TX_CODESPACE.to_string(),
+        ..Default::default()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
 }
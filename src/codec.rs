@@ -8,7 +8,6 @@ use async_iterator::Iterator;
 use bytes::{Buf, BufMut, BytesMut};
 use prost::Message;
 use std::marker::{PhantomData, Unpin};
-use tendermint_proto::v0_37::abci::{Request, Response};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use tendermint_abci::Error;
@@ -17,9 +16,6 @@ use tendermint_abci::Error;
 /// we're encountering a decoding error for a varint.
 pub const MAX_VARINT_LENGTH: usize = 16;
 
-/// The server receives incoming requests, and sends outgoing responses.
-pub type ServerCodec<S> = Codec<S, Request, Response>;
-
 /// Allows for iteration over `S` to produce instances of `I`, as well as
 /// sending instances of `O`.
 pub struct Codec<S, I, O> {
@@ -0,0 +1,649 @@
+use std::collections::{HashMap, HashSet};
+
+use once_cell::sync::Lazy;
+use starknet::core::utils::starknet_keccak;
+use starknet_crypto::Felt as FieldElement;
+use tendermint_proto::v0_37::abci::{Event, EventAttribute};
+
+use crate::blockchain::{Address, Contract};
+use crate::state::{ContractState, DeclaredClass, State, StateCommitment};
+use crate::transaction::{contract_address, Transaction, TransactionType};
+use crate::validation::{
+    parse_deploy, parse_felt, parse_invoke, parse_trie_key, verify_class, verify_hash,
+    verify_signature, TxError,
+};
+
+/// Type of the event emitted for every executed transaction.
+pub const TX_EVENT: &str = "starknet_tx";
+
+/// Storage slot of an account's public key, the one of OpenZeppelin accounts.
+pub static ACCOUNT_PUBLIC_KEY: Lazy<FieldElement> =
+    Lazy::new(|| starknet_keccak(b"Account_public_key"));
+
+/// Storage slot of the account that deployed a contract with Deploy, which
+/// may write the contract's storage besides the contract itself.
+pub static CONTRACT_DEPLOYER: Lazy<FieldElement> =
+    Lazy::new(|| starknet_keccak(b"Contract_deployer"));
+
+/// Read access to the state transactions execute against.
+pub trait StateReader {
+    fn contract(&self, address: &FieldElement) -> Option<ContractState>;
+
+    fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement>;
+
+    fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement>;
+}
+
+/// The working state, including the writes of the block being built.
+impl StateReader for State {
+    fn contract(&self, address: &FieldElement) -> Option<ContractState> {
+        State::contract(self, address).copied()
+    }
+
+    fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement> {
+        State::compiled_class_hash(self, class_hash)
+    }
+
+    fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        State::storage(self, address, key)
+    }
+}
+
+/// The state as of a committed block.
+pub struct CommittedState<'a> {
+    pub state: &'a State,
+    pub commitment: &'a StateCommitment,
+}
+
+impl StateReader for CommittedState<'_> {
+    fn contract(&self, address: &FieldElement) -> Option<ContractState> {
+        self.state.contract_at(self.commitment, address).copied()
+    }
+
+    fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement> {
+        self.state.class_at(self.commitment, class_hash)
+    }
+
+    fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        self.state.storage_at(self.commitment, address, key)
+    }
+}
+
+/// Writes of the transactions waiting in the mempool.
+#[derive(Debug, Default)]
+pub struct PendingWrites {
+    /// Next nonce of the senders of mempool transactions.
+    pub nonces: HashMap<FieldElement, FieldElement>,
+    /// Declared classes and their compiled class hashes.
+    pub classes: HashMap<FieldElement, FieldElement>,
+    /// Deployed contracts and their class hashes.
+    pub contracts: HashMap<FieldElement, FieldElement>,
+    pub storage: HashMap<(FieldElement, FieldElement), FieldElement>,
+}
+
+impl PendingWrites {
+    /// Adds the writes of a batch accepted into the mempool.
+    pub fn extend(&mut self, diff: StateDiff) {
+        self.classes.extend(
+            diff.classes
+                .into_iter()
+                .map(|class| (class.class_hash, class.compiled_class_hash)),
+        );
+        self.contracts.extend(diff.contracts);
+        self.storage.extend(
+            diff.storage
+                .into_iter()
+                .map(|(address, key, value)| ((address, key), value)),
+        );
+        self.nonces.extend(diff.nonces);
+    }
+}
+
+/// A state with the writes of the transactions waiting in the mempool, the
+/// view CheckTx validates new transactions against, so a transaction may
+/// build on a class or an account still in the mempool.
+pub struct PendingState<'a, R> {
+    pub base: &'a R,
+    pub writes: &'a PendingWrites,
+}
+
+impl<R: StateReader> StateReader for PendingState<'_, R> {
+    fn contract(&self, address: &FieldElement) -> Option<ContractState> {
+        let contract = self.base.contract(address).or_else(|| {
+            let class_hash = *self.writes.contracts.get(address)?;
+            Some(ContractState {
+                class_hash,
+                ..Default::default()
+            })
+        })?;
+        Some(match self.writes.nonces.get(address) {
+            Some(nonce) => ContractState {
+                nonce: *nonce,
+                ..contract
+            },
+            None => contract,
+        })
+    }
+
+    fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement> {
+        self.writes
+            .classes
+            .get(class_hash)
+            .copied()
+            .or_else(|| self.base.compiled_class_hash(class_hash))
+    }
+
+    fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        self.writes
+            .storage
+            .get(&(*address, *key))
+            .copied()
+            .or_else(|| self.base.storage(address, key))
+    }
+}
+
+/// Writes of a batch, in execution order.
+#[derive(Clone, Debug, Default)]
+pub struct StateDiff {
+    pub storage: Vec<(FieldElement, FieldElement, FieldElement)>,
+    pub classes: Vec<DeclaredClass>,
+    /// Deployed contracts and their class hashes.
+    pub contracts: Vec<(FieldElement, FieldElement)>,
+    /// Nonces of the senders after the batch.
+    pub nonces: Vec<(FieldElement, FieldElement)>,
+}
+
+impl StateDiff {
+    pub fn apply(self, state: &mut State) {
+        for class in self.classes {
+            state.declare_class(class);
+        }
+        // A contract is deployed before any storage write to it in the batch
+        for (address, class_hash) in self.contracts {
+            state.deploy_contract(address, class_hash);
+        }
+        for (address, key, value) in self.storage {
+            state.set_storage(address, key, value);
+        }
+        for (address, nonce) in self.nonces {
+            state.set_nonce(address, nonce);
+        }
+    }
+}
+
+/// Outcome of a single transaction of a batch.
+pub struct ExecutedTx {
+    pub transaction_hash: String,
+    /// Storage written by the transaction, as recorded in the block.
+    pub writes: HashMap<Address, Contract>,
+    pub event: Event,
+}
+
+/// Executes transactions on top of a base state, collecting their writes in
+/// a diff so a batch only touches the state once every member succeeded.
+pub struct Executor<'a, R> {
+    base: &'a R,
+    chain_id: FieldElement,
+    diff: StateDiff,
+    contracts: HashMap<FieldElement, ContractState>,
+    classes: HashMap<FieldElement, FieldElement>,
+    storage: HashMap<(FieldElement, FieldElement), FieldElement>,
+    seen: HashSet<String>,
+}
+
+// Cloning checkpoints the overlay, the base is shared
+impl<R> Clone for Executor<'_, R> {
+    fn clone(&self) -> Self {
+        Self {
+            base: self.base,
+            chain_id: self.chain_id,
+            diff: self.diff.clone(),
+            contracts: self.contracts.clone(),
+            classes: self.classes.clone(),
+            storage: self.storage.clone(),
+            seen: self.seen.clone(),
+        }
+    }
+}
+
+impl<'a, R: StateReader> Executor<'a, R> {
+    /// Executor of transactions of the chain `chain_id`, see `chain_id_felt`.
+    pub fn new(base: &'a R, chain_id: FieldElement) -> Self {
+        Self {
+            base,
+            chain_id,
+            diff: StateDiff::default(),
+            contracts: HashMap::new(),
+            classes: HashMap::new(),
+            storage: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Executes `tx`, the position of which in the batch is `index`. Nothing
+    /// is recorded if it fails.
+    pub fn execute(&mut self, index: usize, tx: &Transaction) -> Result<ExecutedTx, TxError> {
+        let hash = &tx.transaction_hash;
+        if self.seen.contains(hash) {
+            return Err(TxError::DuplicateTransaction(hash.clone()));
+        }
+        let (sender, nonce) = self.authorize(tx)?;
+
+        let (kind, mut attributes, writes) = match &tx.transaction_type {
+            TransactionType::Declare {
+                class_hash,
+                compiled_class_hash,
+                contract_class,
+            } => {
+                let class_hash = parse_felt("class_hash", class_hash)?;
+                let compiled_class_hash = parse_felt("compiled_class_hash", compiled_class_hash)?;
+                if StateReader::compiled_class_hash(self, &class_hash).is_some() {
+                    return Err(TxError::ClassAlreadyDeclared(format!("0x{class_hash:x}")));
+                }
+                verify_class(&class_hash, contract_class)?;
+                self.classes.insert(class_hash, compiled_class_hash);
+                self.diff.classes.push(DeclaredClass {
+                    class_hash,
+                    compiled_class_hash,
+                    contract_class: contract_class.clone(),
+                });
+                (
+                    "declare",
+                    vec![
+                        attribute("class_hash", format!("0x{class_hash:x}"), true),
+                        attribute(
+                            "compiled_class_hash",
+                            format!("0x{compiled_class_hash:x}"),
+                            false,
+                        ),
+                    ],
+                    HashMap::new(),
+                )
+            }
+            TransactionType::Invoke {
+                address,
+                key,
+                storage,
+            } => {
+                let (contract_address, storage_key, value) = parse_invoke(address, key, storage)?;
+                // Writing to an unused address would squat a future deployment
+                let contract = StateReader::contract(self, &contract_address)
+                    .ok_or_else(|| TxError::UnknownContract(address.clone()))?;
+                // Accounts write their own storage and the one of the contracts
+                // they deployed, only an account itself replaces its public key
+                let deployer = StateReader::storage(self, &contract_address, &CONTRACT_DEPLOYER);
+                let authorized = contract_address == sender
+                    || (deployer == Some(sender) && storage_key != *ACCOUNT_PUBLIC_KEY);
+                if !authorized {
+                    return Err(TxError::Unauthorized {
+                        sender: tx.sender_address.clone(),
+                        address: address.clone(),
+                    });
+                }
+                if contract.class_hash != FieldElement::ZERO
+                    && StateReader::compiled_class_hash(self, &contract.class_hash).is_none()
+                {
+                    return Err(TxError::UnknownClass(format!(
+                        "0x{:x}",
+                        contract.class_hash
+                    )));
+                }
+                self.contracts.insert(contract_address, contract);
+                self.write_storage(contract_address, storage_key, value);
+                (
+                    "invoke",
+                    vec![
+                        attribute("address", address.clone(), true),
+                        attribute("key", key.clone(), false),
+                    ],
+                    HashMap::from([(address.clone(), (key.clone(), storage.clone()))]),
+                )
+            }
+            TransactionType::DeployAccount {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+            } => {
+                let (class_hash, salt, calldata) =
+                    parse_deploy(class_hash, contract_address_salt, constructor_calldata)?;
+                let address = contract_address(class_hash, salt, &calldata, FieldElement::ZERO);
+                if address != sender {
+                    return Err(TxError::UnknownAccount(tx.sender_address.clone()));
+                }
+                self.deploy(address, class_hash)?;
+                // `authorize` checked the signature against this key
+                self.write_storage(address, *ACCOUNT_PUBLIC_KEY, calldata[0]);
+                (
+                    "deploy_account",
+                    vec![
+                        attribute("address", format!("0x{address:x}"), true),
+                        attribute("class_hash", format!("0x{class_hash:x}"), true),
+                    ],
+                    HashMap::new(),
+                )
+            }
+            TransactionType::Deploy {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+                deployer_address,
+            } => {
+                let (class_hash, salt, calldata) =
+                    parse_deploy(class_hash, contract_address_salt, constructor_calldata)?;
+                let deployer = parse_trie_key("deployer_address", deployer_address)?;
+                // The deployer salts the address, so only it may claim it
+                if deployer != sender {
+                    return Err(TxError::DeployerMismatch {
+                        sender: tx.sender_address.clone(),
+                        deployer: deployer_address.clone(),
+                    });
+                }
+                let address = contract_address(class_hash, salt, &calldata, deployer);
+                self.deploy(address, class_hash)?;
+                self.write_storage(address, *CONTRACT_DEPLOYER, deployer);
+                (
+                    "deploy",
+                    vec![
+                        attribute("address", format!("0x{address:x}"), true),
+                        attribute("class_hash", format!("0x{class_hash:x}"), true),
+                        attribute("deployer_address", deployer_address.clone(), true),
+                    ],
+                    HashMap::new(),
+                )
+            }
+        };
+
+        self.seen.insert(hash.clone());
+        self.set_nonce(sender, nonce + FieldElement::ONE);
+        attributes.splice(
+            0..0,
+            [
+                attribute("hash", hash.clone(), true),
+                attribute("type", kind.to_string(), false),
+                attribute("index", index.to_string(), false),
+            ],
+        );
+        Ok(ExecutedTx {
+            transaction_hash: hash.clone(),
+            writes,
+            event: Event {
+                r#type: TX_EVENT.to_string(),
+                attributes,
+            },
+        })
+    }
+
+    /// Verifies that the sender signed the hash of `tx` with the current nonce
+    /// of its account, returning the sender and that nonce.
+    fn authorize(&self, tx: &Transaction) -> Result<(FieldElement, FieldElement), TxError> {
+        let sender = parse_trie_key("sender_address", &tx.sender_address)?;
+        let nonce = parse_felt("nonce", &tx.nonce)?;
+        let unknown = || TxError::UnknownAccount(tx.sender_address.clone());
+        let (public_key, expected_nonce) = match &tx.transaction_type {
+            // A new account signs with the public key it is deployed with
+            TransactionType::DeployAccount {
+                constructor_calldata,
+                ..
+            } => {
+                let public_key = constructor_calldata.first().ok_or_else(unknown)?;
+                (
+                    parse_felt("constructor_calldata", public_key)?,
+                    FieldElement::ZERO,
+                )
+            }
+            _ => {
+                let account = StateReader::contract(self, &sender).ok_or_else(unknown)?;
+                let public_key = StateReader::storage(self, &sender, &ACCOUNT_PUBLIC_KEY)
+                    .filter(|public_key| *public_key != FieldElement::ZERO)
+                    .ok_or_else(unknown)?;
+                (public_key, account.nonce)
+            }
+        };
+        if nonce != expected_nonce {
+            return Err(TxError::InvalidNonce {
+                expected: format!("0x{expected_nonce:x}"),
+                actual: tx.nonce.clone(),
+            });
+        }
+        let hash = verify_hash(tx, sender, nonce, self.chain_id)?;
+        verify_signature(tx, &hash, &public_key)?;
+        Ok((sender, nonce))
+    }
+
+    fn write_storage(&mut self, address: FieldElement, key: FieldElement, value: FieldElement) {
+        self.storage.insert((address, key), value);
+        self.diff.storage.push((address, key, value));
+    }
+
+    fn set_nonce(&mut self, address: FieldElement, nonce: FieldElement) {
+        if let Some(contract) = self.contracts.get_mut(&address) {
+            contract.nonce = nonce;
+        } else if let Some(contract) = self.base.contract(&address) {
+            self.contracts
+                .insert(address, ContractState { nonce, ..contract });
+        }
+        self.diff.nonces.push((address, nonce));
+    }
+
+    /// Instantiates the declared class `class_hash` at the unused `address`.
+    fn deploy(&mut self, address: FieldElement, class_hash: FieldElement) -> Result<(), TxError> {
+        if StateReader::compiled_class_hash(self, &class_hash).is_none() {
+            return Err(TxError::UnknownClass(format!("0x{class_hash:x}")));
+        }
+        if StateReader::contract(self, &address).is_some() {
+            return Err(TxError::ContractAlreadyDeployed(format!("0x{address:x}")));
+        }
+        let contract = ContractState {
+            class_hash,
+            ..Default::default()
+        };
+        self.contracts.insert(address, contract);
+        self.diff.contracts.push((address, class_hash));
+        Ok(())
+    }
+
+    pub fn into_diff(self) -> StateDiff {
+        self.diff
+    }
+}
+
+/// Reads see the writes of the transactions executed so far.
+impl<R: StateReader> StateReader for Executor<'_, R> {
+    fn contract(&self, address: &FieldElement) -> Option<ContractState> {
+        self.contracts
+            .get(address)
+            .copied()
+            .or_else(|| self.base.contract(address))
+    }
+
+    fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement> {
+        self.classes
+            .get(class_hash)
+            .copied()
+            .or_else(|| self.base.compiled_class_hash(class_hash))
+    }
+
+    fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        self.storage
+            .get(&(*address, *key))
+            .copied()
+            .or_else(|| self.base.storage(address, key))
+    }
+}
+
+fn attribute(key: &str, value: String, index: bool) -> EventAttribute {
+    EventAttribute {
+        key: key.to_string(),
+        value,
+        index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_crypto::get_public_key;
+
+    use super::*;
+    use crate::transaction::chain_id_felt;
+
+    const CHAIN_ID: &str = "test-chain";
+
+    /// Account at `address` signing with `private_key`, as created at genesis.
+    fn add_account(state: &mut State, address: u64, private_key: u64) -> (String, FieldElement) {
+        let private_key = FieldElement::from(private_key);
+        let address = FieldElement::from(address);
+        state.deploy_contract(address, FieldElement::ZERO);
+        state.set_storage(address, *ACCOUNT_PUBLIC_KEY, get_public_key(&private_key));
+        (format!("{address:#x}"), private_key)
+    }
+
+    fn signed(
+        transaction_type: TransactionType,
+        (sender, private_key): &(String, FieldElement),
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::with_type(
+            transaction_type,
+            sender.clone(),
+            format!("{nonce:#x}"),
+            CHAIN_ID,
+        )
+        .unwrap()
+        .sign(private_key)
+        .unwrap()
+    }
+
+    fn invoke(address: &str, key: &str) -> TransactionType {
+        TransactionType::Invoke {
+            address: address.to_string(),
+            key: key.to_string(),
+            storage: "0x7".to_string(),
+        }
+    }
+
+    #[test]
+    fn accounts_only_write_their_own_storage() {
+        let mut state = State::new();
+        let a = add_account(&mut state, 0x666, 1);
+        let b = add_account(&mut state, 0x777, 2);
+        state.commit();
+
+        let mut executor = Executor::new(&state, chain_id_felt(CHAIN_ID));
+        assert!(matches!(
+            executor.execute(0, &signed(invoke(&a.0, "0x5"), &b, 0)),
+            Err(TxError::Unauthorized { .. })
+        ));
+        executor
+            .execute(0, &signed(invoke(&a.0, "0x5"), &a, 0))
+            .unwrap();
+        executor
+            .execute(1, &signed(invoke(&b.0, "0x5"), &b, 0))
+            .unwrap();
+    }
+
+    #[test]
+    fn deployer_writes_storage_of_its_contract() {
+        let mut state = State::new();
+        let a = add_account(&mut state, 0x666, 1);
+        let b = add_account(&mut state, 0x777, 2);
+        state.declare_class(DeclaredClass {
+            class_hash: FieldElement::from(0xabcu64),
+            compiled_class_hash: FieldElement::from(0xdefu64),
+            contract_class: String::new(),
+        });
+        state.commit();
+
+        let mut executor = Executor::new(&state, chain_id_felt(CHAIN_ID));
+        let deploy = TransactionType::Deploy {
+            class_hash: "0xabc".to_string(),
+            contract_address_salt: "0x1".to_string(),
+            constructor_calldata: vec![],
+            deployer_address: a.0.clone(),
+        };
+        executor.execute(0, &signed(deploy, &a, 0)).unwrap();
+        let contract = contract_address(
+            FieldElement::from(0xabcu64),
+            FieldElement::ONE,
+            &[],
+            FieldElement::from(0x666u64),
+        );
+        let contract = format!("{contract:#x}");
+
+        executor
+            .execute(1, &signed(invoke(&contract, "0x5"), &a, 1))
+            .unwrap();
+        assert!(matches!(
+            executor.execute(2, &signed(invoke(&contract, "0x5"), &b, 0)),
+            Err(TxError::Unauthorized { .. })
+        ));
+        // The deployer cannot turn the contract into an account
+        let public_key = format!("{:#x}", *ACCOUNT_PUBLIC_KEY);
+        assert!(matches!(
+            executor.execute(2, &signed(invoke(&contract, &public_key), &a, 2)),
+            Err(TxError::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn mempool_writes_are_visible_to_later_transactions() {
+        let mut state = State::new();
+        let a = add_account(&mut state, 0x666, 1);
+        state.commit();
+        let chain_id = chain_id_felt(CHAIN_ID);
+        let class_hash = FieldElement::from(0xabcu64);
+
+        // A Declare of `a` waits in the mempool
+        let mut writes = PendingWrites::default();
+        writes.extend(StateDiff {
+            classes: vec![DeclaredClass {
+                class_hash,
+                compiled_class_hash: FieldElement::from(0xdefu64),
+                contract_class: String::new(),
+            }],
+            nonces: vec![(FieldElement::from(0x666u64), FieldElement::ONE)],
+            ..Default::default()
+        });
+
+        let deploy = TransactionType::Deploy {
+            class_hash: "0xabc".to_string(),
+            contract_address_salt: "0x1".to_string(),
+            constructor_calldata: vec![],
+            deployer_address: a.0.clone(),
+        };
+        let view = PendingState {
+            base: &state,
+            writes: &writes,
+        };
+        Executor::new(&view, chain_id)
+            .execute(0, &signed(deploy, &a, 1))
+            .unwrap();
+
+        let private_key = FieldElement::from(2u64);
+        let public_key = get_public_key(&private_key);
+        let address = contract_address(
+            class_hash,
+            FieldElement::ONE,
+            &[public_key],
+            FieldElement::ZERO,
+        );
+        let account = (format!("{address:#x}"), private_key);
+        let deploy_account = TransactionType::DeployAccount {
+            class_hash: "0xabc".to_string(),
+            contract_address_salt: "0x1".to_string(),
+            constructor_calldata: vec![format!("{public_key:#x}")],
+        };
+        let mut executor = Executor::new(&view, chain_id);
+        executor
+            .execute(0, &signed(deploy_account, &account, 0))
+            .unwrap();
+        writes.extend(executor.into_diff());
+
+        let view = PendingState {
+            base: &state,
+            writes: &writes,
+        };
+        Executor::new(&view, chain_id)
+            .execute(0, &signed(invoke(&account.0, "0x5"), &account, 1))
+            .unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+use serde::Deserialize;
+use starknet_crypto::Felt as FieldElement;
+
+use crate::execution::ACCOUNT_PUBLIC_KEY;
+use crate::state::State;
+use crate::validation::parse_trie_key;
+
+/// Initial state of the chain, read from the `app_state` of the Tendermint
+/// genesis file.
+///
+/// ```json
+/// { "accounts": [{ "address": "0x...", "public_key": "0x..." }] }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Genesis {
+    #[serde(default)]
+    pub accounts: Vec<GenesisAccount>,
+}
+
+/// Account that exists from the start so it can sign the first transactions.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GenesisAccount {
+    pub address: String,
+    pub public_key: String,
+}
+
+impl Genesis {
+    /// An empty `app_state` is an empty genesis.
+    pub fn from_app_state(bytes: &[u8]) -> serde_json::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(bytes)
+    }
+
+    pub fn apply(&self, state: &mut State) -> Result<(), String> {
+        for account in &self.accounts {
+            let address = parse_trie_key("address", &account.address)
+                .map_err(|_| format!("Invalid account address {}", account.address))?;
+            let public_key = FieldElement::from_hex(&account.public_key)
+                .map_err(|_| format!("Invalid public key {}", account.public_key))?;
+            state.deploy_contract(address, FieldElement::ZERO);
+            state.set_storage(address, *ACCOUNT_PUBLIC_KEY, public_key);
+        }
+        Ok(())
+    }
+}
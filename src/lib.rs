@@ -1,6 +1,16 @@
-mod application;
+pub mod application;
 pub mod blockchain;
 mod codec;
+pub mod execution;
+pub mod genesis;
 pub mod proof;
+pub mod proposal;
+pub mod query;
 pub mod server;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
 pub mod transaction;
+pub mod trie;
+pub mod validation;
+pub mod wire;
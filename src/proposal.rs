@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use starknet_crypto::Felt as FieldElement;
+use tracing::info;
+
+use crate::execution::{Executor, StateReader};
+use crate::transaction::Transaction;
+use crate::validation::{decode_batch, TxError};
+use crate::wire;
+
+/// Limits of the blocks the app proposes, on top of the byte budget set by
+/// Tendermint.
+#[derive(Clone, Copy, Debug)]
+pub struct ProposalConfig {
+    /// Most transactions in a block, counting every member of a batch.
+    pub max_block_txs: usize,
+    /// Merges consecutive single-transaction batches into one batch, off by
+    /// default. A merged batch is not the bytes its transactions entered the
+    /// mempool with, so `broadcast_tx_commit` clients do not find them in the
+    /// block and Tendermint only evicts them from the mempool on recheck.
+    pub merge_batches: bool,
+}
+
+impl Default for ProposalConfig {
+    fn default() -> Self {
+        Self {
+            max_block_txs: 1000,
+            merge_batches: false,
+        }
+    }
+}
+
+/// Bytes a transaction takes in a block, with the overhead of the repeated
+/// protobuf field Tendermint counts against `max_tx_bytes`.
+pub fn block_size(tx: &[u8]) -> usize {
+    1 + prost::encoding::encoded_len_varint(tx.len() as u64) + tx.len()
+}
+
+/// Picks the transactions of a block among the mempool transactions `txs`,
+/// executing them on top of `base`.
+///
+/// Undecodable batches and batches that fail are dropped, the batches of
+/// every sender are ordered by nonce and the block stays within
+/// `max_tx_bytes` and `config.max_block_txs`.
+pub fn prepare<R: StateReader>(
+    base: &R,
+    chain_id: FieldElement,
+    is_committed: impl Fn(&str) -> bool,
+    txs: &[impl AsRef<[u8]>],
+    max_tx_bytes: usize,
+    config: &ProposalConfig,
+) -> Vec<Vec<u8>> {
+    let batches: Vec<_> = txs
+        .iter()
+        .filter_map(|tx| Some((tx.as_ref(), decode_batch(tx.as_ref()).ok()?)))
+        .collect();
+    let undecodable = txs.len() - batches.len();
+
+    let mut executor = Executor::new(base, chain_id);
+    let mut selected = vec![];
+    let (mut bytes, mut count) = (0, 0);
+    let mut failed = 0;
+    // A batch with a future nonce may follow a batch selected after it
+    let mut pending = sort_by_nonce(batches);
+    loop {
+        let mut retry = vec![];
+        let selected_before = selected.len();
+        for (tx, batch) in pending {
+            if bytes + block_size(tx) > max_tx_bytes || count + batch.len() > config.max_block_txs {
+                continue;
+            }
+            let mut attempt = executor.clone();
+            match execute_batch(&mut attempt, &batch, &is_committed) {
+                Ok(()) => {
+                    executor = attempt;
+                    bytes += block_size(tx);
+                    count += batch.len();
+                    selected.push((tx, batch));
+                }
+                Err(TxError::InvalidNonce { .. }) => retry.push((tx, batch)),
+                Err(_) => failed += 1,
+            }
+        }
+        if retry.is_empty() || selected.len() == selected_before {
+            failed += retry.len();
+            break;
+        }
+        pending = retry;
+    }
+    if undecodable + failed > 0 {
+        info!(
+            "Left {} undecodable and {} invalid batches out of the proposal",
+            undecodable, failed
+        );
+    }
+
+    if config.merge_batches {
+        let merged = merge_batches(&selected);
+        if merged.iter().map(|tx| block_size(tx)).sum::<usize>() <= max_tx_bytes {
+            return merged;
+        }
+    }
+    selected.into_iter().map(|(tx, _)| tx.to_vec()).collect()
+}
+
+/// Checks a block proposed by another node, executing its transactions on
+/// top of `base` as they would be delivered.
+pub fn process<R: StateReader>(
+    base: &R,
+    chain_id: FieldElement,
+    is_committed: impl Fn(&str) -> bool,
+    txs: &[impl AsRef<[u8]>],
+    config: &ProposalConfig,
+) -> Result<(), ProposalError> {
+    let mut executor = Executor::new(base, chain_id);
+    let mut count = 0;
+    for (index, tx) in txs.iter().enumerate() {
+        let invalid = |error| ProposalError::InvalidBatch { index, error };
+        let batch = decode_batch(tx.as_ref()).map_err(invalid)?;
+        count += batch.len();
+        if count > config.max_block_txs {
+            return Err(ProposalError::TooManyTransactions {
+                max: config.max_block_txs,
+            });
+        }
+        execute_batch(&mut executor, &batch, &is_committed).map_err(invalid)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProposalError {
+    InvalidBatch { index: usize, error: TxError },
+    TooManyTransactions { max: usize },
+}
+
+impl fmt::Display for ProposalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposalError::InvalidBatch { index, error } => write!(f, "Batch {index}: {error}"),
+            ProposalError::TooManyTransactions { max } => {
+                write!(f, "Block holds more than {max} transactions")
+            }
+        }
+    }
+}
+
+/// Executes the whole batch, the caller discards `executor` if it fails.
+pub fn execute_batch<R: StateReader>(
+    executor: &mut Executor<'_, R>,
+    batch: &[Transaction],
+    is_committed: impl Fn(&str) -> bool,
+) -> Result<(), TxError> {
+    for (index, tx) in batch.iter().enumerate() {
+        if is_committed(&tx.transaction_hash) {
+            return Err(TxError::DuplicateTransaction(tx.transaction_hash.clone()));
+        }
+        executor.execute(index, tx)?;
+    }
+    Ok(())
+}
+
+/// Orders the batches of every sender by the nonce of their first
+/// transaction, keeping the positions the sender's batches take.
+fn sort_by_nonce(batches: Vec<(&[u8], Vec<Transaction>)>) -> Vec<(&[u8], Vec<Transaction>)> {
+    let mut positions: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (position, (_, batch)) in batches.iter().enumerate() {
+        positions
+            .entry(&batch[0].sender_address)
+            .or_default()
+            .push(position);
+    }
+    let mut order: Vec<usize> = (0..batches.len()).collect();
+    for positions in positions.into_values() {
+        let mut sorted = positions.clone();
+        sorted.sort_by_key(|&position| {
+            FieldElement::from_hex(&batches[position].1[0].nonce).unwrap_or(FieldElement::MAX)
+        });
+        for (position, batch) in positions.into_iter().zip(sorted) {
+            order[position] = batch;
+        }
+    }
+    let mut batches: Vec<_> = batches.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|position| batches[position].take().expect("order: a permutation"))
+        .collect()
+}
+
+/// Replaces every run of consecutive single-transaction batches with one
+/// batch of the same transactions.
+fn merge_batches(selected: &[(&[u8], Vec<Transaction>)]) -> Vec<Vec<u8>> {
+    let mut merged = vec![];
+    let mut run: Vec<(&[u8], &Transaction)> = vec![];
+    let flush = |run: &mut Vec<(&[u8], &Transaction)>, merged: &mut Vec<Vec<u8>>| {
+        match run.len() {
+            0 => {}
+            1 => merged.push(run[0].0.to_vec()),
+            _ => merged.push(wire::encode_batch(
+                &run.iter().map(|(_, tx)| (*tx).clone()).collect::<Vec<_>>(),
+            )),
+        }
+        run.clear();
+    };
+    for (tx, batch) in selected {
+        match &batch[..] {
+            [single] => run.push((tx, single)),
+            _ => {
+                flush(&mut run, &mut merged);
+                merged.push(tx.to_vec());
+            }
+        }
+    }
+    flush(&mut run, &mut merged);
+    merged
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::str::FromStr;
+
+use starknet_crypto::Felt as FieldElement;
+
+use crate::validation;
+
+pub const QUERY_CODESPACE: &str = "query";
+
+/// Paths served by `Application::query`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// `/storage/{address}/{key}`
+    Storage {
+        address: FieldElement,
+        key: FieldElement,
+    },
+    /// `/proof/{address}/{key}`
+    Proof {
+        address: FieldElement,
+        key: FieldElement,
+    },
+    /// `/nonce/{address}`, at height 0 the next nonce the mempool expects.
+    Nonce { address: FieldElement },
+    /// `/class/{hash}`
+    Class { class_hash: FieldElement },
+    /// `/block/{height}`
+    Block { height: i64 },
+    /// `/tx/{hash}`
+    Transaction { hash: FieldElement },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    UnknownPath(String),
+    InvalidArgument(String),
+    UnknownHeight(i64),
+    NotFound(String),
+    InvalidState(String),
+}
+
+impl QueryError {
+    pub fn code(&self) -> u32 {
+        match self {
+            QueryError::UnknownPath(_) => 1,
+            QueryError::InvalidArgument(_) => 2,
+            QueryError::UnknownHeight(_) => 3,
+            QueryError::NotFound(_) => 4,
+            QueryError::InvalidState(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::UnknownPath(path) => write!(f, "Unknown query path: {path}"),
+            QueryError::InvalidArgument(arg) => write!(f, "Invalid query argument: {arg}"),
+            QueryError::UnknownHeight(height) => write!(f, "No block at height {height}"),
+            QueryError::NotFound(what) => write!(f, "Not found: {what}"),
+            QueryError::InvalidState(what) => write!(f, "Invalid stored state: {what}"),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["storage", address, key] => Ok(Query::Storage {
+                address: parse_trie_key("address", address)?,
+                key: parse_trie_key("key", key)?,
+            }),
+            ["proof", address, key] => Ok(Query::Proof {
+                address: parse_trie_key("address", address)?,
+                key: parse_trie_key("key", key)?,
+            }),
+            ["nonce", address] => Ok(Query::Nonce {
+                address: parse_trie_key("address", address)?,
+            }),
+            ["class", class_hash] => Ok(Query::Class {
+                class_hash: parse_felt(class_hash)?,
+            }),
+            ["block", height] => Ok(Query::Block {
+                height: height
+                    .parse()
+                    .map_err(|_| QueryError::InvalidArgument(height.to_string()))?,
+            }),
+            ["tx", hash] => Ok(Query::Transaction {
+                hash: parse_felt(hash)?,
+            }),
+            _ => Err(QueryError::UnknownPath(path.to_string())),
+        }
+    }
+}
+
+fn parse_felt(value: &str) -> Result<FieldElement, QueryError> {
+    FieldElement::from_hex(value).map_err(|_| QueryError::InvalidArgument(value.to_string()))
+}
+
+/// Addresses and storage keys are trie keys, bounded like the ones of
+/// transactions.
+fn parse_trie_key(field: &'static str, value: &str) -> Result<FieldElement, QueryError> {
+    validation::parse_trie_key(field, value)
+        .map_err(|_| QueryError::InvalidArgument(value.to_string()))
+}
@@ -1,21 +1,33 @@
 use async_iterator::Iterator;
+use prost::Message;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
-use tendermint_abci::{Application, Error};
+use tendermint_abci::Error;
+use tendermint_proto::{v0_37, v0_38};
 use tracing::{error, info};
 
-use crate::application::RequestDispatcher;
-use crate::codec::ServerCodec;
+use crate::application::{AbciVersion, Application, RequestDispatcher};
+use crate::codec::Codec;
 
 pub const DEFAULT_SERVER_READ_BUF_SIZE: usize = 1024 * 1024;
 
 pub struct ServerBuilder {
     read_buf_size: usize,
+    abci_version: AbciVersion,
 }
 
 impl ServerBuilder {
     pub fn new(read_buf_size: usize) -> Self {
-        Self { read_buf_size }
+        Self {
+            read_buf_size,
+            abci_version: AbciVersion::default(),
+        }
+    }
+
+    /// Protocol spoken with the node, which has to match its version.
+    pub fn abci_version(mut self, abci_version: AbciVersion) -> Self {
+        self.abci_version = abci_version;
+        self
     }
 
     pub async fn bind<Addr, App>(self, addr: Addr, app: App) -> Result<Server<App>, Error>
@@ -25,12 +37,16 @@ impl ServerBuilder {
     {
         let listener = TcpListener::bind(addr).await.map_err(Error::io)?;
         let local_addr = listener.local_addr().map_err(Error::io)?.to_string();
-        info!("ABCI server running at {}", local_addr);
+        info!(
+            "ABCI {} server running at {}",
+            self.abci_version, local_addr
+        );
         Ok(Server {
             app,
             listener,
             local_addr,
             read_buf_size: self.read_buf_size,
+            abci_version: self.abci_version,
         })
     }
 }
@@ -40,6 +56,7 @@ pub struct Server<App> {
     listener: TcpListener,
     local_addr: String,
     read_buf_size: usize,
+    abci_version: AbciVersion,
 }
 
 impl<App: Application> Server<App> {
@@ -59,13 +76,42 @@ impl<App: Application> Server<App> {
     fn spawn_client_handler(&self, stream: TcpStream, addr: String) {
         let app = self.app.clone();
         let read_buf_size = self.read_buf_size;
+        let abci_version = self.abci_version;
         let _ = tokio::task::spawn(async move {
-            Self::handle_client(stream, addr, app, read_buf_size).await
+            match abci_version {
+                AbciVersion::V0_37 => {
+                    Self::handle_client::<v0_37::abci::Request, v0_37::abci::Response>(
+                        stream,
+                        addr,
+                        app,
+                        read_buf_size,
+                    )
+                    .await
+                }
+                AbciVersion::V0_38 => {
+                    Self::handle_client::<v0_38::abci::Request, v0_38::abci::Response>(
+                        stream,
+                        addr,
+                        app,
+                        read_buf_size,
+                    )
+                    .await
+                }
+            }
         });
     }
 
-    async fn handle_client(stream: TcpStream, addr: String, app: App, read_buf_size: usize) {
-        let mut codec = ServerCodec::new(stream, read_buf_size);
+    async fn handle_client<Request, Response>(
+        stream: TcpStream,
+        addr: String,
+        app: App,
+        read_buf_size: usize,
+    ) where
+        Request: Message + Default,
+        Response: Message,
+        App: RequestDispatcher<Request, Response>,
+    {
+        let mut codec = Codec::<_, Request, Response>::new(stream, read_buf_size);
         loop {
             let request = match codec.next().await {
                 Some(result) => match result {
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use starknet::core::utils::starknet_keccak;
+use starknet_crypto::{poseidon_hash_many, Felt as FieldElement};
+
+use crate::state::State;
+use crate::storage::StoredBlock;
+
+/// Encoding of the snapshots, a bincode `StoredBlock` whose changes rebuild
+/// the whole state of the block.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// When snapshots are taken and how they are split.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotConfig {
+    /// Snapshots are taken at every multiple of `interval`, 0 disables them.
+    pub interval: u64,
+    /// Number of most recent snapshots served to peers.
+    pub keep_recent: usize,
+    /// Bytes per chunk, Tendermint accepts chunks of up to 16 MB.
+    pub chunk_size: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            interval: 0,
+            keep_recent: 2,
+            chunk_size: 1 << 20,
+        }
+    }
+}
+
+/// State of a committed block split in chunks.
+///
+/// The metadata carries the hashes of the chunks and `hash` commits to them,
+/// so a node restoring the snapshot checks every chunk as it arrives.
+pub struct Snapshot {
+    pub height: u64,
+    pub hash: FieldElement,
+    pub chunk_hashes: Vec<FieldElement>,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// `stored` has to hold the whole state, see `State::snapshot`.
+    pub fn new(stored: &StoredBlock, chunk_size: usize) -> Self {
+        let bytes = bincode::serialize(stored).expect("StoredBlock: serializable");
+        let chunks: Vec<Vec<u8>> = bytes
+            .chunks(chunk_size.max(1))
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        let chunk_hashes: Vec<_> = chunks.iter().map(|chunk| starknet_keccak(chunk)).collect();
+        Self {
+            height: stored.block.height as u64,
+            hash: poseidon_hash_many(&chunk_hashes),
+            chunk_hashes,
+            chunks,
+        }
+    }
+
+    pub fn chunk_count(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    pub fn chunk(&self, index: u32) -> Option<&[u8]> {
+        self.chunks.get(index as usize).map(Vec::as_slice)
+    }
+
+    /// The chunk hashes as consecutive 32 big-endian bytes.
+    pub fn metadata(&self) -> Vec<u8> {
+        self.chunk_hashes
+            .iter()
+            .flat_map(|hash| hash.to_bytes_be())
+            .collect()
+    }
+}
+
+/// Snapshots served to peers, and the one being restored from them.
+#[derive(Default)]
+pub struct Snapshots {
+    config: SnapshotConfig,
+    taken: VecDeque<Snapshot>,
+    pub restore: Option<Restore>,
+}
+
+impl Snapshots {
+    pub fn new(config: SnapshotConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_due(&self, height: i64) -> bool {
+        self.config.interval > 0
+            && height > 0
+            && (height as u64).is_multiple_of(self.config.interval)
+    }
+
+    /// Keeps the snapshot of `stored`, dropping the oldest beyond
+    /// `keep_recent`.
+    pub fn take(&mut self, stored: &StoredBlock) {
+        self.taken
+            .push_back(Snapshot::new(stored, self.config.chunk_size));
+        while self.taken.len() > self.config.keep_recent {
+            self.taken.pop_front();
+        }
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Snapshot> {
+        self.taken.iter()
+    }
+
+    pub fn get(&self, height: u64) -> Option<&Snapshot> {
+        self.taken.iter().find(|snapshot| snapshot.height == height)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    UnknownFormat(u32),
+    InvalidMetadata,
+    UnknownChunk(u32),
+    ChunkHashMismatch(u32),
+    InvalidState(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnknownFormat(format) => write!(f, "Unknown snapshot format {format}"),
+            SnapshotError::InvalidMetadata => {
+                write!(f, "Chunk hashes do not match the snapshot hash")
+            }
+            SnapshotError::UnknownChunk(index) => write!(f, "Unknown chunk {index}"),
+            SnapshotError::ChunkHashMismatch(index) => {
+                write!(f, "Chunk {index} does not match its hash")
+            }
+            SnapshotError::InvalidState(reason) => write!(f, "Invalid snapshot state: {reason}"),
+        }
+    }
+}
+
+/// Snapshot accepted from a peer, filled chunk by chunk.
+pub struct Restore {
+    pub height: u64,
+    // App hash of the height, trusted through the light client
+    app_hash: Vec<u8>,
+    chunk_hashes: Vec<FieldElement>,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Restore {
+    /// Checks an offered snapshot against its own hash before fetching it.
+    pub fn new(
+        height: u64,
+        format: u32,
+        hash: &[u8],
+        metadata: &[u8],
+        app_hash: Vec<u8>,
+    ) -> Result<Self, SnapshotError> {
+        if format != SNAPSHOT_FORMAT {
+            return Err(SnapshotError::UnknownFormat(format));
+        }
+        if metadata.is_empty() || !metadata.len().is_multiple_of(32) {
+            return Err(SnapshotError::InvalidMetadata);
+        }
+        let chunk_hashes: Vec<_> = metadata
+            .chunks(32)
+            .map(FieldElement::from_bytes_be_slice)
+            .collect();
+        if poseidon_hash_many(&chunk_hashes).to_bytes_be()[..] != *hash {
+            return Err(SnapshotError::InvalidMetadata);
+        }
+        Ok(Self {
+            height,
+            app_hash,
+            chunks: vec![None; chunk_hashes.len()],
+            chunk_hashes,
+        })
+    }
+
+    /// Stores chunk `index` and returns whether every chunk has arrived.
+    pub fn apply(&mut self, index: u32, chunk: Vec<u8>) -> Result<bool, SnapshotError> {
+        let expected = self
+            .chunk_hashes
+            .get(index as usize)
+            .ok_or(SnapshotError::UnknownChunk(index))?;
+        if starknet_keccak(&chunk) != *expected {
+            return Err(SnapshotError::ChunkHashMismatch(index));
+        }
+        self.chunks[index as usize] = Some(chunk);
+        Ok(self.chunks.iter().all(Option::is_some))
+    }
+
+    /// Rebuilds the state of the snapshot once every chunk has arrived, and
+    /// the block to persist it as.
+    pub fn finish(self) -> Result<(State, StoredBlock), SnapshotError> {
+        let invalid = |reason: &str| SnapshotError::InvalidState(reason.to_string());
+        let bytes: Vec<u8> = self.chunks.into_iter().flatten().flatten().collect();
+        let stored: StoredBlock =
+            bincode::deserialize(&bytes).map_err(|e| invalid(&e.to_string()))?;
+        if stored.block.height as u64 != self.height {
+            return Err(invalid("block height differs from the snapshot height"));
+        }
+        if stored.block.commitment != stored.changes.commitment {
+            return Err(invalid("block commitment differs from the state"));
+        }
+        if stored.block.commitment.app_hash() != self.app_hash {
+            return Err(invalid("state root differs from the trusted app hash"));
+        }
+        let state = State::from_snapshot(stored.changes.clone()).map_err(|e| invalid(&e))?;
+        Ok((state, stored))
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use starknet_crypto::{pedersen_hash, poseidon_hash, poseidon_hash_many, Felt as FieldElement};
+
+use crate::proof::{ContractData, GetProofResult};
+use crate::storage::felt_bytes;
+use crate::trie::{felt, MerkleTrie, Pedersen, Poseidon, TrieNode};
+use crate::validation::verify_class;
+
+const CONTRACT_STATE_HASH_VERSION: FieldElement = FieldElement::ZERO;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractState {
+    #[serde(with = "felt_bytes")]
+    pub class_hash: FieldElement,
+    #[serde(with = "felt_bytes")]
+    pub nonce: FieldElement,
+    #[serde(with = "felt_bytes")]
+    pub storage_root: FieldElement,
+}
+
+impl ContractState {
+    /// H(H(H(class_hash, storage_root), nonce), CONTRACT_STATE_HASH_VERSION),
+    /// the leaf of the contract in the contracts trie.
+    pub fn hash(&self) -> FieldElement {
+        let hash = pedersen_hash(&self.class_hash, &self.storage_root);
+        let hash = pedersen_hash(&hash, &self.nonce);
+        pedersen_hash(&hash, &CONTRACT_STATE_HASH_VERSION)
+    }
+}
+
+/// Roots of the state tries after a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateCommitment {
+    #[serde(with = "felt_bytes")]
+    pub contracts_root: FieldElement,
+    #[serde(with = "felt_bytes")]
+    pub classes_root: FieldElement,
+    #[serde(with = "felt_bytes")]
+    pub state_root: FieldElement,
+}
+
+impl StateCommitment {
+    fn new(contracts_root: FieldElement, classes_root: FieldElement) -> Self {
+        let global_state_ver = FieldElement::from_bytes_be_slice(b"STARKNET_STATE_V0");
+        Self {
+            contracts_root,
+            classes_root,
+            state_root: poseidon_hash_many(&[global_state_ver, contracts_root, classes_root]),
+        }
+    }
+
+    /// The global state root as the big-endian bytes reported to Tendermint.
+    pub fn app_hash(&self) -> Vec<u8> {
+        self.state_root.to_bytes_be().to_vec()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContractUpdate {
+    #[serde(with = "felt_bytes")]
+    pub address: FieldElement,
+    pub contract: ContractState,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeclaredClass {
+    #[serde(with = "felt_bytes")]
+    pub class_hash: FieldElement,
+    #[serde(with = "felt_bytes")]
+    pub compiled_class_hash: FieldElement,
+    /// The Sierra class as JSON.
+    pub contract_class: String,
+}
+
+/// Everything written to the state since the previous `State::take_changes`,
+/// in the form it is persisted in.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateChanges {
+    pub storage_nodes: Vec<TrieNode>,
+    pub contract_nodes: Vec<TrieNode>,
+    pub class_nodes: Vec<TrieNode>,
+    pub contracts: Vec<ContractUpdate>,
+    pub classes: Vec<DeclaredClass>,
+    pub commitment: StateCommitment,
+}
+
+/// Starknet state of the application.
+///
+/// Every contract's storage is a separate trie, all of them kept in a single
+/// node store. The contracts trie maps addresses to contract state hashes and
+/// the classes trie maps class hashes to compiled class hashes, together they
+/// make up the global state root.
+#[derive(Clone, Default)]
+pub struct State {
+    storage: MerkleTrie<Pedersen>,
+    contracts: HashMap<FieldElement, ContractState>,
+    contracts_trie: MerkleTrie<Pedersen>,
+    // Preimages of the contracts trie leaves, for reads at older roots
+    contract_states: HashMap<FieldElement, ContractState>,
+    classes: HashMap<FieldElement, DeclaredClass>,
+    classes_trie: MerkleTrie<Poseidon>,
+    commitment: StateCommitment,
+    dirty: HashSet<FieldElement>,
+    changes: StateChanges,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            commitment: StateCommitment::new(FieldElement::ZERO, FieldElement::ZERO),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_storage(&mut self, address: FieldElement, key: FieldElement, value: FieldElement) {
+        let contract = self.contracts.entry(address).or_default();
+        contract.storage_root = self.storage.insert(contract.storage_root, &key, value);
+        self.dirty.insert(address);
+    }
+
+    /// Creates the contract at `address` with an empty storage and nonce 0.
+    pub fn deploy_contract(&mut self, address: FieldElement, class_hash: FieldElement) {
+        self.contracts.insert(
+            address,
+            ContractState {
+                class_hash,
+                ..Default::default()
+            },
+        );
+        self.dirty.insert(address);
+    }
+
+    pub fn set_nonce(&mut self, address: FieldElement, nonce: FieldElement) {
+        self.contracts.entry(address).or_default().nonce = nonce;
+        self.dirty.insert(address);
+    }
+
+    pub fn declare_class(&mut self, class: DeclaredClass) {
+        let class_hash = class.class_hash;
+        let leaf = class_leaf(class.compiled_class_hash);
+        self.classes.insert(class_hash, class.clone());
+        self.changes.classes.push(class);
+        let classes_root =
+            self.classes_trie
+                .insert(self.commitment.classes_root, &class_hash, leaf);
+        self.commitment = StateCommitment::new(self.commitment.contracts_root, classes_root);
+    }
+
+    pub fn compiled_class_hash(&self, class_hash: &FieldElement) -> Option<FieldElement> {
+        Some(self.classes.get(class_hash)?.compiled_class_hash)
+    }
+
+    /// Folds the contracts changed since the last call into the contracts trie
+    /// and returns the resulting commitment.
+    pub fn commit(&mut self) -> StateCommitment {
+        let mut contracts_root = self.commitment.contracts_root;
+        for address in self.dirty.drain() {
+            let contract = self.contracts[&address];
+            let leaf = contract.hash();
+            self.contract_states.insert(leaf, contract);
+            self.changes
+                .contracts
+                .push(ContractUpdate { address, contract });
+            contracts_root = self.contracts_trie.insert(contracts_root, &address, leaf);
+        }
+        self.commitment = StateCommitment::new(contracts_root, self.commitment.classes_root);
+        self.commitment
+    }
+
+    /// Drains the changes made since the previous call, to be persisted.
+    pub fn take_changes(&mut self) -> StateChanges {
+        let mut changes = std::mem::take(&mut self.changes);
+        changes.storage_nodes = self.storage.take_new_nodes();
+        changes.contract_nodes = self.contracts_trie.take_new_nodes();
+        changes.class_nodes = self.classes_trie.take_new_nodes();
+        changes.commitment = self.commitment;
+        changes
+    }
+
+    /// Applies persisted changes, in the order they were taken.
+    pub fn load_changes(&mut self, changes: StateChanges) {
+        self.storage.load_nodes(changes.storage_nodes);
+        self.contracts_trie.load_nodes(changes.contract_nodes);
+        self.classes_trie.load_nodes(changes.class_nodes);
+        for ContractUpdate { address, contract } in changes.contracts {
+            self.contract_states.insert(contract.hash(), contract);
+            self.contracts.insert(address, contract);
+        }
+        for class in changes.classes {
+            self.classes.insert(class.class_hash, class);
+        }
+        self.commitment = changes.commitment;
+    }
+
+    /// Everything the state at `commitment` is made of, as changes that
+    /// rebuild it from an empty state. Older versions are left out.
+    pub fn snapshot(&self, commitment: &StateCommitment) -> Option<StateChanges> {
+        let mut contracts = vec![];
+        for (address, leaf) in self.contracts_trie.leaves(commitment.contracts_root)? {
+            let contract = *self.contract_states.get(&leaf)?;
+            contracts.push(ContractUpdate { address, contract });
+        }
+        let mut classes = vec![];
+        for (class_hash, _) in self.classes_trie.leaves(commitment.classes_root)? {
+            classes.push(self.classes.get(&class_hash)?.clone());
+        }
+        Some(StateChanges {
+            storage_nodes: self
+                .storage
+                .nodes(contracts.iter().map(|update| update.contract.storage_root))?,
+            contract_nodes: self.contracts_trie.nodes([commitment.contracts_root])?,
+            class_nodes: self.classes_trie.nodes([commitment.classes_root])?,
+            contracts,
+            classes,
+            commitment: *commitment,
+        })
+    }
+
+    /// Rebuilds the state of a snapshot, checking that it holds every node,
+    /// contract and class below the roots of its commitment.
+    pub fn from_snapshot(snapshot: StateChanges) -> Result<Self, String> {
+        let commitment = snapshot.commitment;
+        if commitment != StateCommitment::new(commitment.contracts_root, commitment.classes_root) {
+            return Err("State root does not match the trie roots".to_string());
+        }
+        let mut state = Self::new();
+        state.load_changes(snapshot);
+
+        let contracts = state
+            .contracts_trie
+            .leaves(commitment.contracts_root)
+            .ok_or("Missing contracts trie nodes")?;
+        if contracts.len() != state.contracts.len() {
+            return Err("Contracts do not match the contracts trie".to_string());
+        }
+        for (address, leaf) in contracts {
+            let contract = state
+                .contracts
+                .get(&address)
+                .filter(|contract| contract.hash() == leaf)
+                .ok_or_else(|| format!("Contract 0x{address:x} does not match its leaf"))?;
+            state
+                .storage
+                .leaves(contract.storage_root)
+                .ok_or_else(|| format!("Missing storage nodes of contract 0x{address:x}"))?;
+        }
+
+        let classes = state
+            .classes_trie
+            .leaves(commitment.classes_root)
+            .ok_or("Missing classes trie nodes")?;
+        if classes.len() != state.classes.len() {
+            return Err("Classes do not match the classes trie".to_string());
+        }
+        for (class_hash, leaf) in classes {
+            let class = state
+                .classes
+                .get(&class_hash)
+                .filter(|class| {
+                    class.class_hash == class_hash && class_leaf(class.compiled_class_hash) == leaf
+                })
+                .ok_or_else(|| format!("Class 0x{class_hash:x} does not match its leaf"))?;
+            // The leaf only commits to the compiled class hash, not the class
+            verify_class(&class_hash, &class.contract_class)
+                .map_err(|e| format!("Class 0x{class_hash:x}: {e}"))?;
+        }
+        Ok(state)
+    }
+
+    pub fn storage(&self, address: &FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        let contract = self.contracts.get(address)?;
+        self.storage.get(contract.storage_root, key)
+    }
+
+    pub fn contract(&self, address: &FieldElement) -> Option<&ContractState> {
+        self.contracts.get(address)
+    }
+
+    /// Contract state as of `commitment`, looked up through the contracts trie.
+    pub fn contract_at(
+        &self,
+        commitment: &StateCommitment,
+        address: &FieldElement,
+    ) -> Option<&ContractState> {
+        let leaf = self
+            .contracts_trie
+            .get(commitment.contracts_root, address)?;
+        self.contract_states.get(&leaf)
+    }
+
+    pub fn storage_at(
+        &self,
+        commitment: &StateCommitment,
+        address: &FieldElement,
+        key: &FieldElement,
+    ) -> Option<FieldElement> {
+        let contract = self.contract_at(commitment, address)?;
+        self.storage.get(contract.storage_root, key)
+    }
+
+    /// Compiled class hash of a class declared as of `commitment`.
+    pub fn class_at(
+        &self,
+        commitment: &StateCommitment,
+        class_hash: &FieldElement,
+    ) -> Option<FieldElement> {
+        self.declared_class_at(commitment, class_hash)
+            .map(|class| class.compiled_class_hash)
+    }
+
+    pub fn declared_class_at(
+        &self,
+        commitment: &StateCommitment,
+        class_hash: &FieldElement,
+    ) -> Option<&DeclaredClass> {
+        self.classes_trie.get(commitment.classes_root, class_hash)?;
+        self.classes.get(class_hash)
+    }
+
+    /// Builds the proof of `keys` in the storage of `address` against the
+    /// state root of `commitment`. Contract data is left out if the contract
+    /// did not exist at that point.
+    pub fn proof(
+        &self,
+        commitment: &StateCommitment,
+        address: &FieldElement,
+        keys: &[FieldElement],
+    ) -> GetProofResult {
+        let contract_data = self
+            .contract_at(commitment, address)
+            .map(|contract| ContractData {
+                class_hash: felt(&contract.class_hash),
+                contract_state_hash_version: felt(&CONTRACT_STATE_HASH_VERSION),
+                nonce: felt(&contract.nonce),
+                root: felt(&contract.storage_root),
+                storage_proofs: Some(
+                    keys.iter()
+                        .map(|key| self.storage.prove(contract.storage_root, key))
+                        .collect(),
+                ),
+            });
+        GetProofResult {
+            class_commitment: Some(felt(&commitment.classes_root)),
+            contract_data,
+            contract_proof: self
+                .contracts_trie
+                .prove(commitment.contracts_root, address),
+            state_commitment: Some(felt(&commitment.state_root)),
+        }
+    }
+}
+
+/// Leaf of a class in the classes trie.
+fn class_leaf(compiled_class_hash: FieldElement) -> FieldElement {
+    poseidon_hash(
+        FieldElement::from_bytes_be_slice(b"CONTRACT_CLASS_LEAF_V0"),
+        compiled_class_hash,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::{Address, StorageKey};
+
+    fn storage_key(key: &FieldElement) -> StorageKey {
+        StorageKey::try_new(&format!("0x{key:063x}")).unwrap()
+    }
+
+    fn verify(
+        proof: &GetProofResult,
+        commitment: &StateCommitment,
+        address: &FieldElement,
+        key: &FieldElement,
+        value: &FieldElement,
+    ) -> bool {
+        proof
+            .verify(
+                felt(&commitment.state_root),
+                Address(felt(address)),
+                storage_key(key),
+                felt(value),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn storage_proofs_verify_against_their_root() {
+        let address = FieldElement::from(0x666u64);
+        let other = FieldElement::from(0x777u64);
+        let key = FieldElement::from(0x10u64);
+
+        let mut state = State::new();
+        state.declare_class(DeclaredClass {
+            class_hash: FieldElement::from(0x1u64),
+            compiled_class_hash: FieldElement::from(0x2u64),
+            contract_class: String::new(),
+        });
+        state.deploy_contract(address, FieldElement::from(0x1u64));
+        state.set_storage(address, key, FieldElement::from(0x11u64));
+        state.set_storage(other, key, FieldElement::from(0x12u64));
+        let first = state.commit();
+        state.set_storage(address, key, FieldElement::from(0x13u64));
+        state.set_storage(address, FieldElement::from(0x14u64), FieldElement::ONE);
+        state.set_nonce(address, FieldElement::ONE);
+        let second = state.commit();
+
+        for (commitment, value) in [(first, 0x11u64), (second, 0x13u64)] {
+            let value = FieldElement::from(value);
+            let proof = state.proof(&commitment, &address, &[key]);
+            assert!(verify(&proof, &commitment, &address, &key, &value));
+            // A tampered value recomputes another root
+            assert!(!verify(
+                &proof,
+                &commitment,
+                &address,
+                &key,
+                &(value + FieldElement::ONE)
+            ));
+        }
+        // A proof does not verify against a later root
+        let proof = state.proof(&first, &address, &[key]);
+        assert!(!verify(
+            &proof,
+            &second,
+            &address,
+            &key,
+            &FieldElement::from(0x11u64)
+        ));
+    }
+
+    #[test]
+    fn commitment_is_independent_of_write_order() {
+        let writes: Vec<_> = (1..20u64)
+            .map(|i| {
+                (
+                    FieldElement::from(0x666 + i % 3),
+                    FieldElement::from(i),
+                    FieldElement::from(i * i),
+                )
+            })
+            .collect();
+
+        let mut forward = State::new();
+        for (address, key, value) in &writes {
+            forward.set_storage(*address, *key, *value);
+        }
+        let mut backward = State::new();
+        backward.set_storage(
+            FieldElement::from(0x667u64),
+            FieldElement::from(0x100u64),
+            FieldElement::ONE,
+        );
+        backward.commit();
+        for (address, key, value) in writes.iter().rev() {
+            backward.set_storage(*address, *key, *value);
+        }
+        backward.set_storage(
+            FieldElement::from(0x667u64),
+            FieldElement::from(0x100u64),
+            FieldElement::ZERO,
+        );
+
+        assert_eq!(forward.commit(), backward.commit());
+    }
+}
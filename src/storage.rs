@@ -0,0 +1,130 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Block;
+use crate::state::StateChanges;
+
+/// A committed block together with the state changes it made, the unit in
+/// which the application persists its chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredBlock {
+    pub block: Block,
+    pub changes: StateChanges,
+}
+
+/// Backend the application persists committed blocks to.
+pub trait Storage: Send {
+    /// Durably appends a committed block.
+    fn append(&mut self, block: &StoredBlock) -> io::Result<()>;
+
+    /// Every block appended so far, in commit order.
+    fn load(&self) -> io::Result<Vec<StoredBlock>>;
+}
+
+/// Keeps blocks in memory only, the chain is lost when the process exits.
+#[derive(Default)]
+pub struct MemoryStorage {
+    blocks: Vec<StoredBlock>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append(&mut self, block: &StoredBlock) -> io::Result<()> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Vec<StoredBlock>> {
+        Ok(self.blocks.clone())
+    }
+}
+
+/// Append-only log file of length-prefixed, bincode encoded blocks.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileStorage {
+    /// Opens the log at `path`, creating it and its parent directories if
+    /// needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Drop a record cut short by a crash so new records follow the last
+        // complete one. It was never acknowledged as committed, so Tendermint
+        // replays that block.
+        let (_, valid_len) = read_log(&path)?;
+        file.set_len(valid_len)?;
+        Ok(Self { path, file })
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&mut self, block: &StoredBlock) -> io::Result<()> {
+        let bytes =
+            bincode::serialize(block).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut record = Vec::with_capacity(bytes.len() + 8);
+        record.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        record.extend_from_slice(&bytes);
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    fn load(&self) -> io::Result<Vec<StoredBlock>> {
+        read_log(&self.path).map(|(blocks, _)| blocks)
+    }
+}
+
+/// Reads the complete records of the log and the length they span.
+fn read_log(path: &Path) -> io::Result<(Vec<StoredBlock>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut blocks = vec![];
+    let mut valid_len = 0;
+    loop {
+        let mut len = [0u8; 8];
+        let mut bytes = match reader.read_exact(&mut len) {
+            Ok(()) => vec![0u8; u64::from_be_bytes(len) as usize],
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let block =
+            bincode::deserialize(&bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        blocks.push(block);
+        valid_len += 8 + bytes.len() as u64;
+    }
+    Ok((blocks, valid_len))
+}
+
+/// Serializes field elements as their 32 big-endian bytes.
+pub(crate) mod felt_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use starknet_crypto::Felt as FieldElement;
+
+    pub fn serialize<S: Serializer>(felt: &FieldElement, serializer: S) -> Result<S::Ok, S::Error> {
+        felt.to_bytes_be().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<FieldElement, D::Error> {
+        let bytes = <[u8; 32]>::deserialize(deserializer)?;
+        Ok(FieldElement::from_bytes_be(&bytes))
+    }
+}
@@ -1,6 +1,10 @@
-use anyhow::Result;
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
-use starknet::core::types::contract::SierraClass;
+use starknet::core::crypto::compute_hash_on_elements;
+use starknet::core::types::contract::{CompiledClass, SierraClass};
+use starknet::core::utils::starknet_keccak;
+use starknet_crypto::{rfc6979_generate_k, sign, Felt as FieldElement};
 use uuid::Uuid;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -8,52 +12,411 @@ pub struct Transaction {
     pub transaction_type: TransactionType,
     pub transaction_hash: String,
     pub id: String,
+    /// Account sending the transaction, the deployed account itself for
+    /// DeployAccount.
+    pub sender_address: String,
+    pub nonce: String,
+    /// `r` and `s` of the sender's signature of the transaction hash.
+    pub signature: Vec<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum TransactionType {
     Declare {
-        program: String,
+        class_hash: String,
+        compiled_class_hash: String,
+        /// The Sierra class as JSON, bincode cannot carry it as a struct.
+        contract_class: String,
     },
     DeployAccount {
-        account: String,
+        class_hash: String,
+        contract_address_salt: String,
+        constructor_calldata: Vec<String>,
     },
     Invoke {
         address: String,
         key: String,
         storage: String,
     },
+    Deploy {
+        class_hash: String,
+        contract_address_salt: String,
+        constructor_calldata: Vec<String>,
+        deployer_address: String,
+    },
+}
+
+/// Reasons building or signing a transaction can fail, each with its own exit
+/// code in the `sequencer` binary.
+#[derive(Debug)]
+pub enum TransactionError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    InvalidFelt {
+        field: &'static str,
+        value: String,
+    },
+    ClassHash {
+        path: String,
+        reason: String,
+    },
+    Sign(String),
+}
+
+impl TransactionError {
+    pub fn code(&self) -> u8 {
+        match self {
+            TransactionError::Io { .. } => 3,
+            TransactionError::Parse { .. } => 4,
+            TransactionError::InvalidFelt { .. } => 5,
+            TransactionError::ClassHash { .. } => 6,
+            TransactionError::Sign(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Io { path, source } => write!(f, "Cannot read {path}: {source}"),
+            TransactionError::Parse { path, source } => {
+                write!(f, "Invalid class in {path}: {source}")
+            }
+            TransactionError::InvalidFelt { field, value } => {
+                write!(f, "Invalid felt {value} for {field}")
+            }
+            TransactionError::ClassHash { path, reason } => {
+                write!(f, "Cannot hash the class in {path}: {reason}")
+            }
+            TransactionError::Sign(reason) => write!(f, "Cannot sign the transaction: {reason}"),
+        }
+    }
 }
 
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::Io { source, .. } => Some(source),
+            TransactionError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+type Result<T, E = TransactionError> = std::result::Result<T, E>;
+
 impl Transaction {
-    pub fn with_type(transaction_type: TransactionType) -> Result<Transaction> {
+    /// Unsigned transaction of `sender_address` on the chain `chain_id`, see
+    /// `Transaction::sign`.
+    pub fn with_type(
+        transaction_type: TransactionType,
+        sender_address: String,
+        nonce: String,
+        chain_id: &str,
+    ) -> Result<Transaction> {
         Ok(Transaction {
-            transaction_hash: transaction_type.compute_and_hash()?,
+            transaction_hash: transaction_type.compute_and_hash(
+                &sender_address,
+                &nonce,
+                chain_id,
+            )?,
             transaction_type,
             id: Uuid::new_v4().to_string(),
+            sender_address,
+            nonce,
+            signature: vec![],
         })
     }
+
+    /// Signs the transaction hash with the private key of the sender account.
+    pub fn sign(mut self, private_key: &FieldElement) -> Result<Transaction> {
+        let message = parse_felt("transaction_hash", &self.transaction_hash)?;
+        let k = rfc6979_generate_k(&message, private_key, None);
+        let signature =
+            sign(private_key, &message, &k).map_err(|e| TransactionError::Sign(e.to_string()))?;
+        self.signature = vec![format!("{:#x}", signature.r), format!("{:#x}", signature.s)];
+        Ok(self)
+    }
 }
 
 impl TransactionType {
-    pub fn compute_and_hash(&self) -> Result<String> {
-        match self {
-            TransactionType::Declare { program } => {
-                let contract = serde_json::from_reader::<_, SierraClass>(
-                    std::fs::File::open(&program).unwrap(),
-                )
-                .unwrap();
-                let class_hash = contract.class_hash().unwrap();
-                Ok(format!("{class_hash:#064x}"))
+    /// Declare of the Sierra class at `program`, compiled to the CASM class at
+    /// `compiled_program`.
+    pub fn declare(program: &str, compiled_program: &str) -> Result<TransactionType> {
+        let compiled_class = read_json::<CompiledClass>(compiled_program)?;
+        let compiled_class_hash =
+            compiled_class
+                .class_hash()
+                .map_err(|e| TransactionError::ClassHash {
+                    path: compiled_program.to_string(),
+                    reason: e.to_string(),
+                })?;
+        Ok(TransactionType::Declare {
+            class_hash: format!("{:#x}", class_hash(program)?),
+            compiled_class_hash: format!("{compiled_class_hash:#x}"),
+            contract_class: std::fs::read_to_string(program).map_err(|source| {
+                TransactionError::Io {
+                    path: program.to_string(),
+                    source,
+                }
+            })?,
+        })
+    }
+
+    /// The transaction hash as a hex felt.
+    pub fn compute_and_hash(
+        &self,
+        sender_address: &str,
+        nonce: &str,
+        chain_id: &str,
+    ) -> Result<String> {
+        let hash = self.compute_hash(
+            parse_felt("sender_address", sender_address)?,
+            parse_felt("nonce", nonce)?,
+            chain_id_felt(chain_id),
+            parse_felt,
+        )?;
+        Ok(format!("{hash:#x}"))
+    }
+
+    /// Starknet hash of the transaction, following the definitions of invoke
+    /// v1, declare v2, deploy account v1 and deploy v0 without fees. `parse`
+    /// reads the felts of the transaction given the name of their field.
+    ///
+    /// Unlike deploy v0, the hash of a deploy ends with the sender and nonce,
+    /// so its signature also covers the nonce the app checks.
+    pub fn compute_hash<E>(
+        &self,
+        sender_address: FieldElement,
+        nonce: FieldElement,
+        chain_id: FieldElement,
+        parse: impl Fn(&'static str, &str) -> Result<FieldElement, E>,
+    ) -> Result<FieldElement, E> {
+        let max_fee = FieldElement::ZERO;
+        let hash = match self {
+            TransactionType::Declare {
+                class_hash,
+                compiled_class_hash,
+                ..
+            } => compute_hash_on_elements(&[
+                FieldElement::from_bytes_be_slice(b"declare"),
+                FieldElement::TWO,
+                sender_address,
+                FieldElement::ZERO,
+                compute_hash_on_elements(&[parse("class_hash", class_hash)?]),
+                max_fee,
+                chain_id,
+                nonce,
+                parse("compiled_class_hash", compiled_class_hash)?,
+            ]),
+            TransactionType::DeployAccount {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+            } => {
+                let mut calldata = vec![
+                    parse("class_hash", class_hash)?,
+                    parse("contract_address_salt", contract_address_salt)?,
+                ];
+                for felt in constructor_calldata {
+                    calldata.push(parse("constructor_calldata", felt)?);
+                }
+                compute_hash_on_elements(&[
+                    FieldElement::from_bytes_be_slice(b"deploy_account"),
+                    FieldElement::ONE,
+                    sender_address,
+                    FieldElement::ZERO,
+                    compute_hash_on_elements(&calldata),
+                    max_fee,
+                    chain_id,
+                    nonce,
+                ])
             }
-            TransactionType::DeployAccount { .. } => Ok("0x1".to_string()),
             TransactionType::Invoke {
                 address,
                 key,
                 storage,
-            } => Ok(format!(
-                "Address: {address}, Key: {key}, Storage: {storage}"
-            )),
+            } => compute_hash_on_elements(&[
+                FieldElement::from_bytes_be_slice(b"invoke"),
+                FieldElement::ONE,
+                sender_address,
+                FieldElement::ZERO,
+                compute_hash_on_elements(&[
+                    parse("address", address)?,
+                    parse("key", key)?,
+                    parse("storage", storage)?,
+                ]),
+                max_fee,
+                chain_id,
+                nonce,
+            ]),
+            TransactionType::Deploy {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+                deployer_address,
+            } => {
+                let calldata = constructor_calldata
+                    .iter()
+                    .map(|felt| parse("constructor_calldata", felt))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let address = contract_address(
+                    parse("class_hash", class_hash)?,
+                    parse("contract_address_salt", contract_address_salt)?,
+                    &calldata,
+                    parse("deployer_address", deployer_address)?,
+                );
+                compute_hash_on_elements(&[
+                    FieldElement::from_bytes_be_slice(b"deploy"),
+                    FieldElement::ZERO,
+                    address,
+                    starknet_keccak(b"constructor"),
+                    compute_hash_on_elements(&calldata),
+                    max_fee,
+                    chain_id,
+                    sender_address,
+                    nonce,
+                ])
+            }
+        };
+        Ok(hash)
+    }
+}
+
+/// The chain id as the short string felt transactions are hashed with.
+pub fn chain_id_felt(chain_id: &str) -> FieldElement {
+    FieldElement::from_bytes_be_slice(chain_id.as_bytes())
+}
+
+/// Class hash of the Sierra class stored as JSON at `program`.
+pub fn class_hash(program: &str) -> Result<FieldElement> {
+    read_json::<SierraClass>(program)?
+        .class_hash()
+        .map_err(|e| TransactionError::ClassHash {
+            path: program.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let file = std::fs::File::open(path).map_err(|source| TransactionError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    serde_json::from_reader(std::io::BufReader::new(file)).map_err(|source| {
+        TransactionError::Parse {
+            path: path.to_string(),
+            source,
         }
+    })
+}
+
+fn parse_felt(field: &'static str, value: &str) -> Result<FieldElement> {
+    FieldElement::from_hex(value).map_err(|_| TransactionError::InvalidFelt {
+        field,
+        value: value.to_string(),
+    })
+}
+
+/// Starknet address of a contract instance, deployer is zero for accounts
+/// deployed through DeployAccount.
+pub fn contract_address(
+    class_hash: FieldElement,
+    salt: FieldElement,
+    constructor_calldata: &[FieldElement],
+    deployer_address: FieldElement,
+) -> FieldElement {
+    starknet::core::utils::get_contract_address(
+        salt,
+        class_hash,
+        constructor_calldata,
+        deployer_address,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected hashes computed with `calculate_transaction_hash` of
+    // starknet_api 0.13, the reference implementation of the sequencer, on
+    // SN_SEPOLIA with a max fee of zero
+    const CHAIN_ID: &str = "SN_SEPOLIA";
+    const SENDER: &str = "0x64b48806902a367c8598f4f95c305e8c1a1acba5f082d294a43793113115691";
+    const CLASS_HASH: &str = "0x5c478ee27f2112411f86f207605b2e2c58cdb647bac0df27f660ef2252359c6";
+
+    fn hash(transaction_type: TransactionType, sender_address: &str, nonce: &str) -> String {
+        transaction_type
+            .compute_and_hash(sender_address, nonce, CHAIN_ID)
+            .unwrap()
+    }
+
+    #[test]
+    fn invoke_hash_is_invoke_v1() {
+        // The calldata of an invoke v1 is the address, key and value
+        let invoke = TransactionType::Invoke {
+            address: SENDER.to_string(),
+            key: "0x361458367e696363fbcc70777d07ebbd2394e89fd0adcaf147faccd1d294d60".to_string(),
+            storage: "0x64696e616d6f".to_string(),
+        };
+        assert_eq!(
+            hash(invoke, SENDER, "0x1"),
+            "0x66ac59aca0a01b32160b30d5b8cebb23a4bf7475028d7c32b22f07e245c106c"
+        );
+    }
+
+    #[test]
+    fn declare_hash_is_declare_v2() {
+        let declare = TransactionType::Declare {
+            class_hash: CLASS_HASH.to_string(),
+            compiled_class_hash:
+                "0x1d6a7c6a9a2f3d5b0b5d0b2b8a4c1c7e9c3e4f2a1b0c9d8e7f6a5b4c3d2e1f0".to_string(),
+            contract_class: String::new(),
+        };
+        assert_eq!(
+            hash(declare, SENDER, "0x0"),
+            "0x2fdd69168c191c0fb8a2a46dc3eaaaa9cd076c32f8f84acd6b9485ad23096f7"
+        );
+    }
+
+    #[test]
+    fn deploy_account_hash_is_deploy_account_v1() {
+        let deploy_account = TransactionType::DeployAccount {
+            class_hash: CLASS_HASH.to_string(),
+            contract_address_salt: "0x2".to_string(),
+            constructor_calldata: vec![
+                "0x39d9e6ce352ad4530a0ef5d5a18fd3303c3606a7fa6ac5b620020ad681cc33b".to_string(),
+            ],
+        };
+        let address = "0x209004e3dbd78ab7d55e422349776b0f70ad84363df06e18bcf3b8198e56ea8";
+        assert_eq!(
+            hash(deploy_account, address, "0x0"),
+            "0x7e273c3a01f7f4a1cb221bf2841b77eb5ba0e74b5b30a6f6d8a76f195cde45d"
+        );
+    }
+
+    #[test]
+    fn deploy_hash_extends_deploy_v0() {
+        let deploy = TransactionType::Deploy {
+            class_hash: CLASS_HASH.to_string(),
+            contract_address_salt: "0x2".to_string(),
+            constructor_calldata: vec!["0x64696e616d6f".to_string()],
+            deployer_address: "0x0".to_string(),
+        };
+        // Deploy v0 ends with the chain id, ours also hashes the sender and
+        // nonce so the signature covers them, which changes the hash
+        let deployed = hash(deploy.clone(), SENDER, "0x0");
+        assert_ne!(
+            deployed,
+            "0x5fb51afc814fa1497b081ced7aa2a0f9955f88a7a769dc3c9355484732e9431"
+        );
+        assert_ne!(deployed, hash(deploy.clone(), "0x1", "0x0"));
+        assert_ne!(deployed, hash(deploy, SENDER, "0x1"));
     }
 }
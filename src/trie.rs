@@ -0,0 +1,540 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use bitvec::view::BitView;
+use serde::{Deserialize, Serialize};
+use starknet_crypto::{pedersen_hash, poseidon_hash, Felt as FieldElement};
+
+use crate::proof::{
+    BinaryNode, BinaryNodeBinary, EdgeNode, EdgeNodeEdge, EdgeNodePath, Felt, Node,
+};
+use crate::storage::felt_bytes;
+
+/// Height of every Starknet trie, keys are 251-bit field elements.
+pub const TRIE_HEIGHT: usize = 251;
+
+/// Hash function used to commit to the nodes of a trie.
+pub trait TrieHash {
+    fn hash(left: &FieldElement, right: &FieldElement) -> FieldElement;
+}
+
+/// Used by the contract storage tries and the contracts trie.
+pub struct Pedersen;
+
+impl TrieHash for Pedersen {
+    fn hash(left: &FieldElement, right: &FieldElement) -> FieldElement {
+        pedersen_hash(left, right)
+    }
+}
+
+/// Used by the classes trie.
+pub struct Poseidon;
+
+impl TrieHash for Poseidon {
+    fn hash(left: &FieldElement, right: &FieldElement) -> FieldElement {
+        poseidon_hash(*left, *right)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "StoredNode", try_from = "StoredNode")]
+pub enum TrieNode {
+    Binary {
+        left: FieldElement,
+        right: FieldElement,
+    },
+    Edge {
+        child: FieldElement,
+        path: BitVec<u8, Msb0>,
+    },
+}
+
+impl TrieNode {
+    pub fn hash<H: TrieHash>(&self) -> FieldElement {
+        match self {
+            TrieNode::Binary { left, right } => H::hash(left, right),
+            TrieNode::Edge { child, path } => {
+                H::hash(child, &path_to_felt(path)) + FieldElement::from(path.len() as u64)
+            }
+        }
+    }
+
+    fn to_proof_node(&self) -> Node {
+        match self {
+            TrieNode::Binary { left, right } => Node::BinaryNode(BinaryNode {
+                binary: BinaryNodeBinary {
+                    left: felt(left),
+                    right: felt(right),
+                },
+            }),
+            TrieNode::Edge { child, path } => Node::EdgeNode(EdgeNode {
+                edge: EdgeNodeEdge {
+                    child: felt(child),
+                    path: EdgeNodePath {
+                        len: path.len() as i64,
+                        value: felt(&path_to_felt(path)),
+                    },
+                },
+            }),
+        }
+    }
+}
+
+/// Encoding of `TrieNode` with the edge path stored as a field element.
+#[derive(Serialize, Deserialize)]
+enum StoredNode {
+    Binary {
+        #[serde(with = "felt_bytes")]
+        left: FieldElement,
+        #[serde(with = "felt_bytes")]
+        right: FieldElement,
+    },
+    Edge {
+        #[serde(with = "felt_bytes")]
+        child: FieldElement,
+        len: usize,
+        #[serde(with = "felt_bytes")]
+        path: FieldElement,
+    },
+}
+
+impl From<TrieNode> for StoredNode {
+    fn from(node: TrieNode) -> Self {
+        match node {
+            TrieNode::Binary { left, right } => StoredNode::Binary { left, right },
+            TrieNode::Edge { child, path } => StoredNode::Edge {
+                child,
+                len: path.len(),
+                path: path_to_felt(&path),
+            },
+        }
+    }
+}
+
+// Snapshots from peers are decoded too, so the length is not trusted
+impl TryFrom<StoredNode> for TrieNode {
+    type Error = String;
+
+    fn try_from(node: StoredNode) -> Result<Self, Self::Error> {
+        match node {
+            StoredNode::Binary { left, right } => Ok(TrieNode::Binary { left, right }),
+            StoredNode::Edge { len, .. } if len > TRIE_HEIGHT => {
+                Err(format!("Edge of length {len} exceeds the trie height"))
+            }
+            StoredNode::Edge { child, len, path } => Ok(TrieNode::Edge {
+                child,
+                path: path.to_bytes_be().view_bits::<Msb0>()[256 - len..].to_bitvec(),
+            }),
+        }
+    }
+}
+
+/// Content-addressed node store for binary Merkle-Patricia tries.
+///
+/// Nodes are keyed by their hash and never overwritten, so a single store
+/// holds any number of tries and every root it has produced stays readable.
+/// The empty trie has the root `0` and writing `0` to a key removes it.
+pub struct MerkleTrie<H> {
+    nodes: HashMap<FieldElement, TrieNode>,
+    // Nodes added since the last `take_new_nodes`
+    new_nodes: Vec<FieldElement>,
+    _hash: PhantomData<H>,
+}
+
+impl<H> Clone for MerkleTrie<H> {
+    fn clone(&self) -> Self {
+        Self {
+            nodes: self.nodes.clone(),
+            new_nodes: self.new_nodes.clone(),
+            _hash: PhantomData,
+        }
+    }
+}
+
+impl<H> Default for MerkleTrie<H> {
+    fn default() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            new_nodes: vec![],
+            _hash: PhantomData,
+        }
+    }
+}
+
+impl<H: TrieHash> MerkleTrie<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drains the nodes added since the previous call, to be persisted.
+    pub fn take_new_nodes(&mut self) -> Vec<TrieNode> {
+        self.new_nodes
+            .drain(..)
+            .map(|hash| self.nodes[&hash].clone())
+            .collect()
+    }
+
+    /// Adds previously persisted nodes back to the store.
+    pub fn load_nodes(&mut self, nodes: impl IntoIterator<Item = TrieNode>) {
+        for node in nodes {
+            self.nodes.insert(node.hash::<H>(), node);
+        }
+    }
+
+    /// Writes `value` under `key` in the trie rooted at `root` and returns
+    /// the new root.
+    pub fn insert(
+        &mut self,
+        root: FieldElement,
+        key: &FieldElement,
+        value: FieldElement,
+    ) -> FieldElement {
+        let key = key_to_bits(key);
+        self.insert_at(root, &key, value)
+    }
+
+    /// Reads the value stored under `key`, `None` if the key is not set.
+    pub fn get(&self, root: FieldElement, key: &FieldElement) -> Option<FieldElement> {
+        let key = key_to_bits(key);
+        let mut hash = root;
+        let mut depth = 0;
+        while depth < TRIE_HEIGHT {
+            match self.nodes.get(&hash)? {
+                TrieNode::Binary { left, right } => {
+                    hash = if key[depth] { *right } else { *left };
+                    depth += 1;
+                }
+                TrieNode::Edge { child, path } => {
+                    if key[depth..depth + path.len()] != path[..] {
+                        return None;
+                    }
+                    hash = *child;
+                    depth += path.len();
+                }
+            }
+        }
+        Some(hash)
+    }
+
+    /// Collects the nodes on the path from `root` towards `key`, ordered from
+    /// the root down, in the format checked by `GetProofResult::verify`.
+    pub fn prove(&self, root: FieldElement, key: &FieldElement) -> Vec<Node> {
+        let key = key_to_bits(key);
+        let mut proof = vec![];
+        let mut hash = root;
+        let mut depth = 0;
+        while depth < TRIE_HEIGHT {
+            let Some(node) = self.nodes.get(&hash) else {
+                break;
+            };
+            proof.push(node.to_proof_node());
+            match node {
+                TrieNode::Binary { left, right } => {
+                    hash = if key[depth] { *right } else { *left };
+                    depth += 1;
+                }
+                TrieNode::Edge { child, path } => {
+                    if key[depth..depth + path.len()] != path[..] {
+                        break;
+                    }
+                    hash = *child;
+                    depth += path.len();
+                }
+            }
+        }
+        proof
+    }
+
+    /// Nodes of the tries rooted at `roots`, each node once. `None` if one of
+    /// them is missing from the store.
+    pub fn nodes(&self, roots: impl IntoIterator<Item = FieldElement>) -> Option<Vec<TrieNode>> {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        for root in roots {
+            self.walk(root, |hash, node| {
+                if visited.insert(hash) {
+                    nodes.push(node.clone());
+                }
+            })?;
+        }
+        Some(nodes)
+    }
+
+    /// Keys and values set in the trie rooted at `root`, `None` if one of its
+    /// nodes is missing from the store.
+    pub fn leaves(&self, root: FieldElement) -> Option<Vec<(FieldElement, FieldElement)>> {
+        self.walk(root, |_, _| ())
+    }
+
+    /// Visits every node below `root` and returns the leaves.
+    fn walk(
+        &self,
+        root: FieldElement,
+        mut visit: impl FnMut(FieldElement, &TrieNode),
+    ) -> Option<Vec<(FieldElement, FieldElement)>> {
+        let mut leaves = vec![];
+        if root == FieldElement::ZERO {
+            return Some(leaves);
+        }
+        let mut stack = vec![(root, BitVec::<u8, Msb0>::new())];
+        while let Some((hash, path)) = stack.pop() {
+            if path.len() >= TRIE_HEIGHT {
+                // An edge running past the leaves is not a valid trie
+                if path.len() > TRIE_HEIGHT {
+                    return None;
+                }
+                leaves.push((path_to_felt(&path), hash));
+                continue;
+            }
+            let node = self.nodes.get(&hash)?;
+            visit(hash, node);
+            match node {
+                TrieNode::Binary { left, right } => {
+                    let mut left_path = path.clone();
+                    left_path.push(false);
+                    let mut right_path = path;
+                    right_path.push(true);
+                    stack.push((*right, right_path));
+                    stack.push((*left, left_path));
+                }
+                TrieNode::Edge { child, path: edge } => {
+                    let mut child_path = path;
+                    child_path.extend_from_bitslice(edge);
+                    stack.push((*child, child_path));
+                }
+            }
+        }
+        Some(leaves)
+    }
+
+    fn insert_at(
+        &mut self,
+        hash: FieldElement,
+        key: &BitSlice<u8, Msb0>,
+        value: FieldElement,
+    ) -> FieldElement {
+        if key.is_empty() {
+            return value;
+        }
+        if hash == FieldElement::ZERO {
+            if value == FieldElement::ZERO {
+                return hash;
+            }
+            return self.edge(key.to_bitvec(), value, true);
+        }
+
+        let node = self
+            .nodes
+            .get(&hash)
+            .cloned()
+            .expect("MerkleTrie: dangling node hash");
+        match node {
+            TrieNode::Binary { left, right } => {
+                let (left, right) = if key[0] {
+                    (left, self.insert_at(right, &key[1..], value))
+                } else {
+                    (self.insert_at(left, &key[1..], value), right)
+                };
+                match (left == FieldElement::ZERO, right == FieldElement::ZERO) {
+                    (true, true) => FieldElement::ZERO,
+                    // A binary node with a single child collapses into an edge
+                    (true, false) => self.edge(BitVec::repeat(true, 1), right, key.len() == 1),
+                    (false, true) => self.edge(BitVec::repeat(false, 1), left, key.len() == 1),
+                    (false, false) => self.store(TrieNode::Binary { left, right }),
+                }
+            }
+            TrieNode::Edge { child, path } => {
+                let common = path
+                    .iter()
+                    .zip(key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if common == path.len() {
+                    let child = self.insert_at(child, &key[common..], value);
+                    if child == FieldElement::ZERO {
+                        return FieldElement::ZERO;
+                    }
+                    return self.edge(path, child, common == key.len());
+                }
+                if value == FieldElement::ZERO {
+                    // Removing a key that is not part of the trie
+                    return hash;
+                }
+
+                let existing = self.edge(
+                    path[common + 1..].to_bitvec(),
+                    child,
+                    path.len() == key.len(),
+                );
+                let inserted = self.edge(key[common + 1..].to_bitvec(), value, true);
+                let binary = if key[common] {
+                    TrieNode::Binary {
+                        left: existing,
+                        right: inserted,
+                    }
+                } else {
+                    TrieNode::Binary {
+                        left: inserted,
+                        right: existing,
+                    }
+                };
+                let binary = self.store(binary);
+                self.edge(path[..common].to_bitvec(), binary, false)
+            }
+        }
+    }
+
+    /// Stores an edge node with the given path above `child`, merging it with
+    /// `child` if that is an edge node itself.
+    fn edge(
+        &mut self,
+        mut path: BitVec<u8, Msb0>,
+        child: FieldElement,
+        child_is_leaf: bool,
+    ) -> FieldElement {
+        if path.is_empty() {
+            return child;
+        }
+        let mut child = child;
+        if !child_is_leaf {
+            if let Some(TrieNode::Edge {
+                child: grandchild,
+                path: child_path,
+            }) = self.nodes.get(&child)
+            {
+                path.extend_from_bitslice(child_path.as_bitslice());
+                child = *grandchild;
+            }
+        }
+        self.store(TrieNode::Edge { child, path })
+    }
+
+    fn store(&mut self, node: TrieNode) -> FieldElement {
+        let hash = node.hash::<H>();
+        if let Entry::Vacant(entry) = self.nodes.entry(hash) {
+            entry.insert(node);
+            self.new_nodes.push(hash);
+        }
+        hash
+    }
+}
+
+/// Whether `key` is below 2^251, `key_to_bits` drops the bits above.
+pub fn is_trie_key(key: &FieldElement) -> bool {
+    key.to_bytes_be().view_bits::<Msb0>()[..256 - TRIE_HEIGHT].not_any()
+}
+
+fn key_to_bits(key: &FieldElement) -> BitVec<u8, Msb0> {
+    key.to_bytes_be().view_bits::<Msb0>()[256 - TRIE_HEIGHT..].to_bitvec()
+}
+
+fn path_to_felt(path: &BitSlice<u8, Msb0>) -> FieldElement {
+    let mut bytes = [0u8; 32];
+    bytes.view_bits_mut::<Msb0>()[256 - path.len()..].copy_from_bitslice(path);
+    FieldElement::from_bytes_be(&bytes)
+}
+
+pub(crate) fn felt(value: &FieldElement) -> Felt {
+    Felt::try_new(&format!("0x{:x}", value)).expect("Felt: formatted field element")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic trie keys and values, below 2^251.
+    fn felts(seed: &mut u64, n: usize) -> Vec<FieldElement> {
+        (0..n)
+            .map(|_| {
+                let mut bytes = [0u8; 32];
+                for byte in bytes.iter_mut() {
+                    *seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    *byte = (*seed >> 33) as u8;
+                }
+                bytes[0] &= 0x07;
+                FieldElement::from_bytes_be(&bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn root_is_independent_of_write_order() {
+        let mut seed = 7;
+        let mut trie = MerkleTrie::<Pedersen>::new();
+        for n in [1, 2, 3, 10, 50] {
+            let keys = felts(&mut seed, n);
+            let values = felts(&mut seed, n);
+            let extra = felts(&mut seed, 1)[0];
+
+            let mut root = FieldElement::ZERO;
+            for (key, value) in keys.iter().zip(&values) {
+                root = trie.insert(root, key, *value);
+            }
+            let mut reversed = trie.insert(FieldElement::ZERO, &extra, FieldElement::ONE);
+            for (key, value) in keys.iter().zip(&values).rev() {
+                reversed = trie.insert(reversed, key, *value);
+            }
+            reversed = trie.insert(reversed, &extra, FieldElement::ZERO);
+
+            assert_eq!(root, reversed, "{n} keys");
+            for (key, value) in keys.iter().zip(&values) {
+                assert_eq!(trie.get(root, key), Some(*value));
+            }
+            assert_eq!(trie.get(root, &extra), None);
+        }
+    }
+
+    #[test]
+    fn deleting_every_key_empties_the_trie() {
+        let mut seed = 11;
+        let mut trie = MerkleTrie::<Poseidon>::new();
+        let keys = felts(&mut seed, 20);
+        let mut root = FieldElement::ZERO;
+        for key in &keys {
+            root = trie.insert(root, key, FieldElement::ONE);
+        }
+        for key in keys.iter().rev() {
+            root = trie.insert(root, key, FieldElement::ZERO);
+        }
+        assert_eq!(root, FieldElement::ZERO);
+    }
+
+    #[test]
+    fn root_follows_the_starknet_node_hashes() {
+        use starknet_crypto::pedersen_hash;
+
+        let mut trie = MerkleTrie::<Pedersen>::new();
+        let (first, second) = (FieldElement::from(3u64), FieldElement::from(4u64));
+
+        // A lone leaf hangs below an edge of all 251 bits of its key
+        let root = trie.insert(FieldElement::ZERO, &FieldElement::from(5u64), first);
+        assert_eq!(
+            root,
+            pedersen_hash(&first, &FieldElement::from(5u64)) + FieldElement::from(251u64)
+        );
+
+        // Keys 0 and 1 branch at the last bit, below an edge of 250 zeros
+        let root = trie.insert(FieldElement::ZERO, &FieldElement::ZERO, first);
+        let root = trie.insert(root, &FieldElement::ONE, second);
+        assert_eq!(
+            root,
+            pedersen_hash(&pedersen_hash(&first, &second), &FieldElement::ZERO)
+                + FieldElement::from(250u64)
+        );
+    }
+
+    #[test]
+    fn trie_key_bound() {
+        assert!(is_trie_key(&FieldElement::ZERO));
+        assert!(is_trie_key(
+            &FieldElement::from_hex(&format!("0x7{}", "f".repeat(62))).unwrap()
+        ));
+        assert!(!is_trie_key(
+            &FieldElement::from_hex(&format!("0x8{}", "0".repeat(62))).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+
+use starknet::core::types::contract::SierraClass;
+use starknet_crypto::{verify, Felt as FieldElement};
+
+use crate::proof::Felt;
+use crate::transaction::Transaction;
+use crate::trie::is_trie_key;
+use crate::wire;
+
+pub const TX_CODESPACE: &str = "tx";
+
+/// Reasons for rejecting a transaction batch, each with its own ABCI code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TxError {
+    Decode(String),
+    EmptyBatch,
+    InvalidFelt { field: &'static str, value: String },
+    InvalidTrieKey { field: &'static str, value: String },
+    UnknownClass(String),
+    ClassAlreadyDeclared(String),
+    DuplicateTransaction(String),
+    OutsideBlock,
+    ContractAlreadyDeployed(String),
+    UnknownAccount(String),
+    InvalidSignature(String),
+    InvalidNonce { expected: String, actual: String },
+    Unauthorized { sender: String, address: String },
+    InvalidHash { expected: String, actual: String },
+    InvalidClass(String),
+    ClassHashMismatch { expected: String, actual: String },
+    UnknownContract(String),
+    DeployerMismatch { sender: String, deployer: String },
+}
+
+impl TxError {
+    pub fn code(&self) -> u32 {
+        match self {
+            TxError::Decode(_) => 1,
+            TxError::EmptyBatch => 2,
+            TxError::InvalidFelt { .. } => 3,
+            TxError::InvalidTrieKey { .. } => 4,
+            TxError::UnknownClass(_) => 5,
+            TxError::ClassAlreadyDeclared(_) => 6,
+            TxError::DuplicateTransaction(_) => 7,
+            TxError::OutsideBlock => 8,
+            TxError::ContractAlreadyDeployed(_) => 9,
+            TxError::UnknownAccount(_) => 10,
+            TxError::InvalidSignature(_) => 11,
+            TxError::InvalidNonce { .. } => 12,
+            TxError::Unauthorized { .. } => 13,
+            TxError::InvalidHash { .. } => 14,
+            TxError::InvalidClass(_) => 15,
+            TxError::ClassHashMismatch { .. } => 16,
+            TxError::UnknownContract(_) => 17,
+            TxError::DeployerMismatch { .. } => 18,
+        }
+    }
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Decode(e) => write!(f, "Failed to decode transaction batch: {e}"),
+            TxError::EmptyBatch => write!(f, "Transaction batch is empty"),
+            TxError::InvalidFelt { field, value } => {
+                write!(f, "Invalid felt for {field}: {value}")
+            }
+            TxError::InvalidTrieKey { field, value } => {
+                write!(f, "Invalid {field}, expected a felt below 2^251: {value}")
+            }
+            TxError::UnknownClass(class_hash) => write!(f, "Class {class_hash} is not declared"),
+            TxError::ClassAlreadyDeclared(class_hash) => {
+                write!(f, "Class {class_hash} is already declared")
+            }
+            TxError::DuplicateTransaction(hash) => write!(f, "Transaction {hash} already seen"),
+            TxError::OutsideBlock => write!(f, "Transaction delivered outside of a block"),
+            TxError::ContractAlreadyDeployed(address) => {
+                write!(f, "A contract is already deployed at {address}")
+            }
+            TxError::UnknownAccount(address) => {
+                write!(f, "No account with a public key at {address}")
+            }
+            TxError::InvalidSignature(hash) => {
+                write!(f, "Invalid signature for transaction {hash}")
+            }
+            TxError::InvalidNonce { expected, actual } => {
+                write!(f, "Invalid nonce {actual}, expected {expected}")
+            }
+            TxError::InvalidHash { expected, actual } => {
+                write!(f, "Invalid transaction hash {actual}, expected {expected}")
+            }
+            TxError::InvalidClass(e) => write!(f, "Invalid Sierra class: {e}"),
+            TxError::ClassHashMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Class hash {actual} does not match the class, expected {expected}"
+                )
+            }
+            TxError::Unauthorized { sender, address } => {
+                write!(f, "Account {sender} may not write the storage of {address}")
+            }
+            TxError::UnknownContract(address) => {
+                write!(f, "No contract is deployed at {address}")
+            }
+            TxError::DeployerMismatch { sender, deployer } => {
+                write!(f, "Account {sender} may not deploy as {deployer}")
+            }
+        }
+    }
+}
+
+pub fn decode_batch(bytes: &[u8]) -> Result<Vec<Transaction>, TxError> {
+    let txs = wire::decode_batch(bytes).map_err(|e| TxError::Decode(e.to_string()))?;
+    if txs.is_empty() {
+        return Err(TxError::EmptyBatch);
+    }
+    Ok(txs)
+}
+
+pub fn parse_felt(field: &'static str, value: &str) -> Result<FieldElement, TxError> {
+    let invalid = || TxError::InvalidFelt {
+        field,
+        value: value.to_string(),
+    };
+    let felt = Felt::try_new(value).map_err(|_| invalid())?;
+    FieldElement::from_hex(felt.as_ref()).map_err(|_| invalid())
+}
+
+/// Storage keys and contract addresses index the state tries, so they have to
+/// fit in 251 bits.
+pub fn parse_trie_key(field: &'static str, value: &str) -> Result<FieldElement, TxError> {
+    let invalid = || TxError::InvalidTrieKey {
+        field,
+        value: value.to_string(),
+    };
+    let key = parse_felt(field, value).map_err(|_| invalid())?;
+    // Values above the field prime are reduced, which would alias another key
+    if !is_trie_key(&key) || !value[2..].eq_ignore_ascii_case(&format!("{key:x}")) {
+        return Err(invalid());
+    }
+    Ok(key)
+}
+
+/// Contract address, storage key and value of an Invoke.
+pub fn parse_invoke(
+    address: &str,
+    key: &str,
+    storage: &str,
+) -> Result<(FieldElement, FieldElement, FieldElement), TxError> {
+    Ok((
+        parse_trie_key("address", address)?,
+        parse_trie_key("key", key)?,
+        parse_felt("storage", storage)?,
+    ))
+}
+
+/// Class hash, salt and constructor calldata of a Deploy or DeployAccount.
+pub fn parse_deploy(
+    class_hash: &str,
+    contract_address_salt: &str,
+    constructor_calldata: &[String],
+) -> Result<(FieldElement, FieldElement, Vec<FieldElement>), TxError> {
+    Ok((
+        parse_felt("class_hash", class_hash)?,
+        parse_felt("contract_address_salt", contract_address_salt)?,
+        constructor_calldata
+            .iter()
+            .map(|felt| parse_felt("constructor_calldata", felt))
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+/// Recomputes the hash of the Sierra class `contract_class` of a Declare,
+/// which has to be the declared `class_hash`.
+pub fn verify_class(class_hash: &FieldElement, contract_class: &str) -> Result<(), TxError> {
+    let class: SierraClass =
+        serde_json::from_str(contract_class).map_err(|e| TxError::InvalidClass(e.to_string()))?;
+    let expected = class
+        .class_hash()
+        .map_err(|e| TxError::InvalidClass(e.to_string()))?;
+    if expected != *class_hash {
+        return Err(TxError::ClassHashMismatch {
+            expected: format!("0x{expected:x}"),
+            actual: format!("0x{class_hash:x}"),
+        });
+    }
+    Ok(())
+}
+
+/// Recomputes the hash of `tx` on `chain_id`, which has to be the one it
+/// claims in its canonical form as it also identifies the transaction.
+pub fn verify_hash(
+    tx: &Transaction,
+    sender: FieldElement,
+    nonce: FieldElement,
+    chain_id: FieldElement,
+) -> Result<FieldElement, TxError> {
+    let hash = tx
+        .transaction_type
+        .compute_hash(sender, nonce, chain_id, parse_felt)?;
+    let expected = format!("{hash:#x}");
+    if tx.transaction_hash != expected {
+        return Err(TxError::InvalidHash {
+            expected,
+            actual: tx.transaction_hash.clone(),
+        });
+    }
+    Ok(hash)
+}
+
+/// Checks the sender's signature of the transaction hash against
+/// `public_key`.
+pub fn verify_signature(
+    tx: &Transaction,
+    hash: &FieldElement,
+    public_key: &FieldElement,
+) -> Result<(), TxError> {
+    let invalid = || TxError::InvalidSignature(tx.transaction_hash.clone());
+    let [r, s] = tx.signature.as_slice() else {
+        return Err(invalid());
+    };
+    let (r, s) = (parse_felt("signature", r)?, parse_felt("signature", s)?);
+    match verify(public_key, hash, &r, &s) {
+        Ok(true) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::{Transaction, TransactionType};
+
+/// Prefix of every versioned transaction batch, followed by the version byte.
+///
+/// Batches without it are version 0, a bare `bincode` encoded
+/// `Vec<LegacyTransaction>`. Its first bytes are the little-endian batch
+/// length, which would have to be in the millions to collide with the magic.
+pub const MAGIC: &[u8; 3] = b"STX";
+/// Protobuf encoded `TransactionBatch`.
+pub const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WireError {
+    UnsupportedVersion(u8),
+    Decode { version: u8, reason: String },
+    MissingType(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported transaction encoding version {version}")
+            }
+            WireError::Decode { version, reason } => {
+                write!(f, "Invalid version {version} transaction batch: {reason}")
+            }
+            WireError::MissingType(id) => write!(f, "Transaction {id} has no type"),
+        }
+    }
+}
+
+/// Encodes `txs` in the current version, as sent in a single Tendermint tx.
+pub fn encode_batch(txs: &[Transaction]) -> Vec<u8> {
+    let batch = TransactionBatch {
+        transactions: txs.iter().map(TransactionMessage::from).collect(),
+    };
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + batch.encoded_len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    batch
+        .encode(&mut bytes)
+        .expect("Vec grows to fit the batch");
+    bytes
+}
+
+/// Decodes a batch of a supported version.
+///
+/// Version 0 batches are recognized but rejected with `UnsupportedVersion`,
+/// their transactions carry neither sender, nonce nor signature and no
+/// longer execute.
+pub fn decode_batch(bytes: &[u8]) -> Result<Vec<Transaction>, WireError> {
+    let Some(payload) = bytes.strip_prefix(MAGIC) else {
+        return Err(
+            match bincode::deserialize::<Vec<LegacyTransaction>>(bytes) {
+                Ok(_) => WireError::UnsupportedVersion(0),
+                Err(e) => WireError::Decode {
+                    version: 0,
+                    reason: e.to_string(),
+                },
+            },
+        );
+    };
+    match payload.split_first() {
+        Some((&VERSION, payload)) => TransactionBatch::decode(payload)
+            .map_err(|e| WireError::Decode {
+                version: VERSION,
+                reason: e.to_string(),
+            })?
+            .transactions
+            .into_iter()
+            .map(Transaction::try_from)
+            .collect(),
+        Some((&version, _)) => Err(WireError::UnsupportedVersion(version)),
+        None => Err(WireError::Decode {
+            version: VERSION,
+            reason: "missing version".to_string(),
+        }),
+    }
+}
+
+// Schema of version 0, frozen as the unsigned transactions were sent.
+
+#[derive(Serialize, Deserialize)]
+struct LegacyTransaction {
+    transaction_type: LegacyTransactionType,
+    transaction_hash: String,
+    id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LegacyTransactionType {
+    Declare {
+        program: String,
+    },
+    DeployAccount {
+        account: String,
+    },
+    Invoke {
+        address: String,
+        key: String,
+        storage: String,
+    },
+}
+
+// Schema of version 1. Tags are never reused, new fields and transaction
+// types take new tags so older batches keep decoding.
+
+#[derive(Clone, PartialEq, Message)]
+struct TransactionBatch {
+    #[prost(message, repeated, tag = "1")]
+    transactions: Vec<TransactionMessage>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TransactionMessage {
+    #[prost(string, tag = "1")]
+    transaction_hash: String,
+    #[prost(string, tag = "2")]
+    id: String,
+    #[prost(string, tag = "3")]
+    sender_address: String,
+    #[prost(string, tag = "4")]
+    nonce: String,
+    #[prost(string, repeated, tag = "5")]
+    signature: Vec<String>,
+    #[prost(oneof = "TypeMessage", tags = "6, 7, 8, 9")]
+    transaction_type: Option<TypeMessage>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum TypeMessage {
+    #[prost(message, tag = "6")]
+    Declare(DeclareMessage),
+    #[prost(message, tag = "7")]
+    DeployAccount(DeployAccountMessage),
+    #[prost(message, tag = "8")]
+    Invoke(InvokeMessage),
+    #[prost(message, tag = "9")]
+    Deploy(DeployMessage),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DeclareMessage {
+    #[prost(string, tag = "1")]
+    class_hash: String,
+    #[prost(string, tag = "2")]
+    compiled_class_hash: String,
+    #[prost(string, tag = "3")]
+    contract_class: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DeployAccountMessage {
+    #[prost(string, tag = "1")]
+    class_hash: String,
+    #[prost(string, tag = "2")]
+    contract_address_salt: String,
+    #[prost(string, repeated, tag = "3")]
+    constructor_calldata: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InvokeMessage {
+    #[prost(string, tag = "1")]
+    address: String,
+    #[prost(string, tag = "2")]
+    key: String,
+    #[prost(string, tag = "3")]
+    storage: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DeployMessage {
+    #[prost(string, tag = "1")]
+    class_hash: String,
+    #[prost(string, tag = "2")]
+    contract_address_salt: String,
+    #[prost(string, repeated, tag = "3")]
+    constructor_calldata: Vec<String>,
+    #[prost(string, tag = "4")]
+    deployer_address: String,
+}
+
+impl From<&Transaction> for TransactionMessage {
+    fn from(tx: &Transaction) -> Self {
+        let transaction_type = match tx.transaction_type.clone() {
+            TransactionType::Declare {
+                class_hash,
+                compiled_class_hash,
+                contract_class,
+            } => TypeMessage::Declare(DeclareMessage {
+                class_hash,
+                compiled_class_hash,
+                contract_class,
+            }),
+            TransactionType::DeployAccount {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+            } => TypeMessage::DeployAccount(DeployAccountMessage {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+            }),
+            TransactionType::Invoke {
+                address,
+                key,
+                storage,
+            } => TypeMessage::Invoke(InvokeMessage {
+                address,
+                key,
+                storage,
+            }),
+            TransactionType::Deploy {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+                deployer_address,
+            } => TypeMessage::Deploy(DeployMessage {
+                class_hash,
+                contract_address_salt,
+                constructor_calldata,
+                deployer_address,
+            }),
+        };
+        Self {
+            transaction_hash: tx.transaction_hash.clone(),
+            id: tx.id.clone(),
+            sender_address: tx.sender_address.clone(),
+            nonce: tx.nonce.clone(),
+            signature: tx.signature.clone(),
+            transaction_type: Some(transaction_type),
+        }
+    }
+}
+
+impl TryFrom<TransactionMessage> for Transaction {
+    type Error = WireError;
+
+    fn try_from(message: TransactionMessage) -> Result<Self, Self::Error> {
+        let transaction_type = match message.transaction_type {
+            Some(TypeMessage::Declare(declare)) => TransactionType::Declare {
+                class_hash: declare.class_hash,
+                compiled_class_hash: declare.compiled_class_hash,
+                contract_class: declare.contract_class,
+            },
+            Some(TypeMessage::DeployAccount(deploy)) => TransactionType::DeployAccount {
+                class_hash: deploy.class_hash,
+                contract_address_salt: deploy.contract_address_salt,
+                constructor_calldata: deploy.constructor_calldata,
+            },
+            Some(TypeMessage::Invoke(invoke)) => TransactionType::Invoke {
+                address: invoke.address,
+                key: invoke.key,
+                storage: invoke.storage,
+            },
+            Some(TypeMessage::Deploy(deploy)) => TransactionType::Deploy {
+                class_hash: deploy.class_hash,
+                contract_address_salt: deploy.contract_address_salt,
+                constructor_calldata: deploy.constructor_calldata,
+                deployer_address: deploy.deployer_address,
+            },
+            None => return Err(WireError::MissingType(message.id)),
+        };
+        Ok(Transaction {
+            transaction_type,
+            transaction_hash: message.transaction_hash,
+            id: message.id,
+            sender_address: message.sender_address,
+            nonce: message.nonce,
+            signature: message.signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(transaction_type: TransactionType) -> Transaction {
+        Transaction {
+            transaction_type,
+            transaction_hash: "0x1".to_string(),
+            id: "id".to_string(),
+            sender_address: "0x2".to_string(),
+            nonce: "0x3".to_string(),
+            signature: vec!["0x4".to_string(), "0x5".to_string()],
+        }
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let txs = vec![
+            transaction(TransactionType::Declare {
+                class_hash: "0x6".to_string(),
+                compiled_class_hash: "0x7".to_string(),
+                contract_class: "{}".to_string(),
+            }),
+            transaction(TransactionType::DeployAccount {
+                class_hash: "0x6".to_string(),
+                contract_address_salt: "0x8".to_string(),
+                constructor_calldata: vec!["0x9".to_string()],
+            }),
+            transaction(TransactionType::Invoke {
+                address: "0xa".to_string(),
+                key: "0xb".to_string(),
+                storage: "0xc".to_string(),
+            }),
+            transaction(TransactionType::Deploy {
+                class_hash: "0x6".to_string(),
+                contract_address_salt: "0x8".to_string(),
+                constructor_calldata: vec![],
+                deployer_address: "0x2".to_string(),
+            }),
+        ];
+        let bytes = encode_batch(&txs);
+        assert_eq!(&bytes[..4], b"STX\x01");
+        let decoded = decode_batch(&bytes).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{txs:?}"));
+    }
+
+    #[test]
+    fn legacy_batch_is_rejected() {
+        let legacy = vec![LegacyTransaction {
+            transaction_type: LegacyTransactionType::Invoke {
+                address: "0xa".to_string(),
+                key: "0xb".to_string(),
+                storage: "0xc".to_string(),
+            },
+            transaction_hash: "Address: 0xa, Key: 0xb, Storage: 0xc".to_string(),
+            id: "id".to_string(),
+        }];
+        let bytes = bincode::serialize(&legacy).unwrap();
+        assert!(matches!(
+            decode_batch(&bytes),
+            Err(WireError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn unknown_bytes_are_rejected() {
+        assert!(matches!(
+            decode_batch(b"junk"),
+            Err(WireError::Decode { version: 0, .. })
+        ));
+        assert!(matches!(
+            decode_batch(b"STX\x02"),
+            Err(WireError::UnsupportedVersion(2))
+        ));
+    }
+}